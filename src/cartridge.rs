@@ -0,0 +1,401 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::mbc::{MBC, MBC1, MBC2, MBC2_RAM_SIZE, MBC3, MBC5, RTC};
+
+// Header
+const HEADER_END: usize = 0x014F;
+const TITLE_BEGIN: usize = 0x0134;
+const TITLE_END: usize = 0x0143;
+const CGB_FLAG: usize = 0x0143;
+const NEW_LICENSEE_CODE: usize = 0x0144;
+const SGB_FLAG: usize = 0x0146;
+const CARTRIDGE_TYPE: usize = 0x0147;
+const ROM_SIZE: usize = 0x0148;
+const RAM_SIZE: usize = 0x0149;
+const OLD_LICENSEE_CODE: usize = 0x014B;
+const VERSION: usize = 0x014C;
+const HEADER_CHECKSUM: usize = 0x014D;
+const GLOBAL_CHECKSUM: usize = 0x014E;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    // The image is too short to even contain a header
+    Truncated(usize),
+    // The file length disagrees with the ROM size declared in the header
+    RomSizeMismatch { declared: usize, actual: usize },
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    UnsupportedCartridgeType(u8),
+    HeaderChecksum { expected: u8, computed: u8 },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(error) => write!(f, "could not read ROM: {}", error),
+            CartridgeError::Truncated(len) => write!(f, "ROM is only {} bytes, too short for a header", len),
+            CartridgeError::RomSizeMismatch { declared, actual } => {
+                write!(f, "header declares {} bytes of ROM but the image has {}", declared, actual)
+            }
+            CartridgeError::InvalidRomSize(code) => write!(f, "unknown ROM size code {:#04x}", code),
+            CartridgeError::InvalidRamSize(code) => write!(f, "unknown RAM size code {:#04x}", code),
+            CartridgeError::UnsupportedCartridgeType(code) => write!(f, "unsupported cartridge type {:#04x}", code),
+            CartridgeError::HeaderChecksum { expected, computed } => {
+                write!(f, "header checksum is {:#04x} but should be {:#04x}", computed, expected)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(error: io::Error) -> Self {
+        CartridgeError::Io(error)
+    }
+}
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum CgbSupport {
+    None,
+    Supported,
+    Required,
+}
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum Controller {
+    RomOnly,
+    MBC1,
+    MBC2,
+    MBC3,
+    MBC5,
+}
+
+#[derive(Copy,Clone,Debug)]
+pub struct CartridgeType {
+    pub controller: Controller,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl CartridgeType {
+    fn from_byte(byte: u8) -> Result<CartridgeType, CartridgeError> {
+        let (controller, ram, battery, timer, rumble) = match byte {
+            0x00 => (Controller::RomOnly, false, false, false, false),
+            0x01 => (Controller::MBC1, false, false, false, false),
+            0x02 => (Controller::MBC1, true, false, false, false),
+            0x03 => (Controller::MBC1, true, true, false, false),
+            0x05 => (Controller::MBC2, false, false, false, false),
+            0x06 => (Controller::MBC2, false, true, false, false),
+            0x08 => (Controller::RomOnly, true, false, false, false),
+            0x09 => (Controller::RomOnly, true, true, false, false),
+            0x0F => (Controller::MBC3, false, true, true, false),
+            0x10 => (Controller::MBC3, true, true, true, false),
+            0x11 => (Controller::MBC3, false, false, false, false),
+            0x12 => (Controller::MBC3, true, false, false, false),
+            0x13 => (Controller::MBC3, true, true, false, false),
+            0x19 => (Controller::MBC5, false, false, false, false),
+            0x1A => (Controller::MBC5, true, false, false, false),
+            0x1B => (Controller::MBC5, true, true, false, false),
+            0x1C => (Controller::MBC5, false, false, false, true),
+            0x1D => (Controller::MBC5, true, false, false, true),
+            0x1E => (Controller::MBC5, true, true, false, true),
+            _ => return Err(CartridgeError::UnsupportedCartridgeType(byte)),
+        };
+        Ok(CartridgeType { controller, ram, battery, timer, rumble })
+    }
+}
+
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    // Two ASCII characters when the old code is 0x33, otherwise the old one byte code
+    pub licensee: String,
+    pub version: u8,
+    // Hardware never checks the global checksum and hacks or homebrew often get it wrong, so a mismatch is only reported
+    pub global_checksum_valid: bool,
+}
+
+impl CartridgeHeader {
+    fn parse(rom: &[u8]) -> Result<CartridgeHeader, CartridgeError> {
+        let cgb = match rom[CGB_FLAG] {
+            0x80 => CgbSupport::Supported,
+            0xC0 => CgbSupport::Required,
+            _ => CgbSupport::None,
+        };
+        // Colour games reuse the last byte of the title for the CGB flag
+        let title_end = if cgb == CgbSupport::None { TITLE_END } else { CGB_FLAG - 1 };
+        let title = rom[TITLE_BEGIN..=title_end]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+
+        let licensee = if rom[OLD_LICENSEE_CODE] == 0x33 {
+            rom[NEW_LICENSEE_CODE..NEW_LICENSEE_CODE + 2].iter().map(|&byte| byte as char).collect()
+        } else {
+            format!("{:02X}", rom[OLD_LICENSEE_CODE])
+        };
+
+        let rom_size = match rom[ROM_SIZE] {
+            code @ 0x00..=0x08 => (ROM_BANK_SIZE * 2) << code,
+            code => return Err(CartridgeError::InvalidRomSize(code)),
+        };
+        let ram_size = match rom[RAM_SIZE] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => RAM_BANK_SIZE,
+            0x03 => RAM_BANK_SIZE * 4,
+            0x04 => RAM_BANK_SIZE * 16,
+            0x05 => RAM_BANK_SIZE * 8,
+            code => return Err(CartridgeError::InvalidRamSize(code)),
+        };
+
+        let expected_global_checksum = (rom[GLOBAL_CHECKSUM] as u16) << 8 | rom[GLOBAL_CHECKSUM + 1] as u16;
+
+        Ok(CartridgeHeader {
+            title,
+            cgb,
+            sgb: rom[SGB_FLAG] == 0x03,
+            cartridge_type: CartridgeType::from_byte(rom[CARTRIDGE_TYPE])?,
+            rom_size,
+            ram_size,
+            licensee,
+            version: rom[VERSION],
+            global_checksum_valid: expected_global_checksum == global_checksum(rom),
+        })
+    }
+}
+// Header
+
+pub struct Cartridge {
+    pub header: CartridgeHeader,
+    pub rom: Vec<u8>,
+    pub ram: Vec<u8>,
+    mbc: MBC,
+    // Where battery-backed RAM is kept, next to the ROM with a .sav extension
+    pub save_path: Option<PathBuf>,
+    ram_dirty: bool,
+}
+
+impl Cartridge {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Cartridge, CartridgeError> {
+        let path = path.as_ref();
+        let mut cartridge = Cartridge::from_bytes(fs::read(path)?)?;
+        if cartridge.header.cartridge_type.battery {
+            cartridge.save_path = Some(path.with_extension("sav"));
+            cartridge.load_save()?;
+        }
+        Ok(cartridge)
+    }
+
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        if rom.len() <= HEADER_END {
+            return Err(CartridgeError::Truncated(rom.len()));
+        }
+
+        let expected = rom[HEADER_CHECKSUM];
+        let computed = header_checksum(&rom);
+        if expected != computed {
+            return Err(CartridgeError::HeaderChecksum { expected, computed });
+        }
+
+        let header = CartridgeHeader::parse(&rom)?;
+        if header.rom_size != rom.len() {
+            return Err(CartridgeError::RomSizeMismatch { declared: header.rom_size, actual: rom.len() });
+        }
+
+        let cartridge_type = header.cartridge_type;
+        let mbc = match cartridge_type.controller {
+            Controller::RomOnly => MBC::RomOnly,
+            Controller::MBC1 => MBC::MBC1(MBC1::new(&rom)),
+            Controller::MBC2 => MBC::MBC2(MBC2::new()),
+            Controller::MBC3 => MBC::MBC3(MBC3::new(cartridge_type.timer)),
+            Controller::MBC5 => MBC::MBC5(MBC5::new(cartridge_type.rumble)),
+        };
+        let ram_size = match cartridge_type.controller {
+            // MBC2 carts declare no RAM in the header as it lives inside the controller
+            Controller::MBC2 => MBC2_RAM_SIZE,
+            _ if cartridge_type.ram => header.ram_size,
+            _ => 0,
+        };
+
+        Ok(Cartridge {
+            header,
+            rom,
+            ram: vec![0; ram_size],
+            mbc,
+            save_path: None,
+            ram_dirty: false,
+        })
+    }
+
+    // Saves are the raw contents of external RAM, with the MBC3 clock appended after it
+    fn load_save(&mut self) -> io::Result<()> {
+        let path = match &self.save_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error),
+        };
+
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            if let Some(saved) = RTC::from_save_bytes(&data[len..]) {
+                *rtc = saved;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = match &self.save_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut data = self.ram.clone();
+        if let Some(rtc) = self.mbc.rtc_mut() {
+            data.extend_from_slice(&rtc.save_bytes());
+        }
+        fs::write(path, data)?;
+        self.ram_dirty = false;
+        Ok(())
+    }
+
+    // Only writes when the game touched RAM since the last save, for periodic flushing
+    pub fn save_if_dirty(&mut self) -> io::Result<()> {
+        if self.ram_dirty {
+            self.save()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn set_rumble_callback(&mut self, callback: Box<dyn FnMut(bool)>) {
+        if let MBC::MBC5(mbc) = &mut self.mbc {
+            mbc.set_rumble_callback(callback);
+        }
+    }
+
+    // 0x0000-0x7FFF, writes go to the bank controller registers
+    pub fn read_rom(&self, address: u16) -> u8 {
+        self.mbc.read_rom(&self.rom, address)
+    }
+
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        self.mbc.write_rom(address, value);
+    }
+
+    // 0xA000-0xBFFF
+    pub fn read_ram(&self, address: u16) -> u8 {
+        self.mbc.read_ram(&self.ram, address)
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        self.mbc.write_ram(&mut self.ram, address, value);
+        self.ram_dirty = true;
+    }
+}
+
+// x = x - byte - 1 over 0x0134..=0x014C, the boot ROM refuses to start a cartridge that gets this wrong
+fn header_checksum(rom: &[u8]) -> u8 {
+    rom[TITLE_BEGIN..=VERSION]
+        .iter()
+        .fold(0u8, |checksum, &byte| checksum.wrapping_sub(byte).wrapping_sub(1))
+}
+
+// Sum of every byte in the image except the two checksum bytes themselves
+fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(address, _)| address != GLOBAL_CHECKSUM && address != GLOBAL_CHECKSUM + 1)
+        .fold(0u16, |checksum, (_, &byte)| checksum.wrapping_add(byte as u16))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mbc::{RTC_SAVE_SIZE, RTC_SAVE_SIZE_LEGACY};
+
+    // MBC3+TIMER+RAM+BATTERY with 32 KiB of ROM and 8 KiB of RAM, saving to a file of its own
+    fn timer_cartridge(save_name: &str) -> Cartridge {
+        let mut rom = vec![0; ROM_BANK_SIZE * 2];
+        rom[CARTRIDGE_TYPE] = 0x10;
+        rom[RAM_SIZE] = 0x02;
+        rom[HEADER_CHECKSUM] = header_checksum(&rom);
+        let mut cartridge = Cartridge::from_bytes(rom).unwrap();
+        cartridge.save_path = Some(std::env::temp_dir().join(format!("{}-{}.sav", save_name, std::process::id())));
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge
+    }
+
+    fn read_rtc(cartridge: &mut Cartridge, register: u8) -> u8 {
+        cartridge.write_rom(0x4000, register);
+        cartridge.read_ram(0xA000)
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let mut cartridge = timer_cartridge("gb-save-round-trip");
+        cartridge.write_ram(0xA000, 0x12);
+        cartridge.write_ram(0xBFFF, 0x34);
+        // Stop the clock with 42 seconds on it
+        cartridge.write_rom(0x4000, 0x0C);
+        cartridge.write_ram(0xA000, 0x40);
+        cartridge.write_rom(0x4000, 0x08);
+        cartridge.write_ram(0xA000, 42);
+        cartridge.save().unwrap();
+
+        let path = cartridge.save_path.clone().unwrap();
+        assert_eq!(fs::read(&path).unwrap().len(), RAM_BANK_SIZE + RTC_SAVE_SIZE);
+
+        let mut loaded = timer_cartridge("gb-save-round-trip");
+        loaded.load_save().unwrap();
+        fs::remove_file(&path).unwrap();
+        loaded.write_rom(0x4000, 0x00);
+        assert_eq!(loaded.read_ram(0xA000), 0x12);
+        assert_eq!(loaded.read_ram(0xBFFF), 0x34);
+        assert_eq!(read_rtc(&mut loaded, 0x08), 42);
+        assert_eq!(read_rtc(&mut loaded, 0x0C), 0x40);
+    }
+
+    #[test]
+    fn loads_legacy_save() {
+        let mut cartridge = timer_cartridge("gb-save-legacy");
+        let mut data = vec![0; RAM_BANK_SIZE + RTC_SAVE_SIZE_LEGACY];
+        data[0] = 0x56;
+        // Stopped clock at 3 hours, latched at 2 hours
+        data[RAM_BANK_SIZE + 8] = 3;
+        data[RAM_BANK_SIZE + 16] = 0x40;
+        data[RAM_BANK_SIZE + 28] = 2;
+        let path = cartridge.save_path.clone().unwrap();
+        fs::write(&path, data).unwrap();
+
+        cartridge.load_save().unwrap();
+        fs::remove_file(&path).unwrap();
+        cartridge.write_rom(0x4000, 0x00);
+        assert_eq!(cartridge.read_ram(0xA000), 0x56);
+        assert_eq!(read_rtc(&mut cartridge, 0x0A), 2);
+        cartridge.write_rom(0x6000, 0x00);
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut cartridge, 0x0A), 3);
+    }
+}
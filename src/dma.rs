@@ -0,0 +1,109 @@
+// OAM DMA copies 160 bytes, one per machine cycle
+const TRANSFER_LENGTH: u8 = 160;
+
+#[derive(Copy,Clone,PartialEq)]
+enum DmaState {
+    Idle,
+    // The transfer begins one machine cycle after the register is written. A transfer
+    // already running copies one more byte meanwhile, this is the index of it
+    Starting(Option<u8>),
+    // Index of the next byte to copy
+    Transferring(u8),
+}
+
+pub struct Dma {
+    // The last value written to 0xFF46, the high byte of the source address
+    pub register: u8,
+    state: DmaState,
+    // Where the running transfer reads from, the register may already point at the next one
+    source: u16,
+}
+
+impl Dma {
+    pub fn new() -> Dma {
+        Dma {
+            register: 0,
+            state: DmaState::Idle,
+            source: 0,
+        }
+    }
+
+    pub fn start(&mut self, value: u8) {
+        self.register = value;
+        self.state = DmaState::Starting(match self.state {
+            DmaState::Transferring(index) => Some(index),
+            DmaState::Starting(running) => running,
+            DmaState::Idle => None,
+        });
+    }
+
+    pub fn is_active(&self) -> bool {
+        matches!(self.state, DmaState::Transferring(_) | DmaState::Starting(Some(_)))
+    }
+
+    // Advances one machine cycle, returns the source address and OAM index of the byte to copy in it
+    pub fn tick(&mut self) -> Option<(u16, usize)> {
+        match self.state {
+            DmaState::Idle => None,
+            DmaState::Starting(running) => {
+                let copy = running.map(|index| (self.source + index as u16, index as usize));
+                self.state = DmaState::Transferring(0);
+                self.source = self.register_source();
+                copy
+            }
+            DmaState::Transferring(index) => {
+                self.state = if index + 1 == TRANSFER_LENGTH {
+                    DmaState::Idle
+                } else {
+                    DmaState::Transferring(index + 1)
+                };
+                Some((self.source + index as u16, index as usize))
+            }
+        }
+    }
+
+    // Sources from 0xE000 up do not reach echo RAM, OAM or the I/O registers. The DMA unit
+    // only drives the external bus there, so they land in working RAM 0x2000 lower.
+    fn register_source(&self) -> u16 {
+        let source = (self.register as u16) << 8;
+        if source >= 0xE000 {
+            source - 0x2000
+        } else {
+            source
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copies_one_byte_per_cycle_after_a_delay() {
+        let mut dma = Dma::new();
+        dma.start(0xC1);
+        assert!(!dma.is_active());
+        assert_eq!(dma.tick(), None);
+        for index in 0..TRANSFER_LENGTH as usize {
+            assert!(dma.is_active());
+            assert_eq!(dma.tick(), Some((0xC100 + index as u16, index)));
+        }
+        assert!(!dma.is_active());
+        assert_eq!(dma.tick(), None);
+    }
+
+    #[test]
+    fn restart_keeps_the_bus_blocked() {
+        let mut dma = Dma::new();
+        dma.start(0xC1);
+        dma.tick();
+        dma.tick();
+        dma.start(0xFE);
+        // The old transfer carries on for the cycle the new one takes to start
+        assert!(dma.is_active());
+        assert_eq!(dma.tick(), Some((0xC101, 1)));
+        assert!(dma.is_active());
+        assert_eq!(dma.tick(), Some((0xDE00, 0)));
+        assert_eq!(dma.register, 0xFE);
+    }
+}
@@ -1,5 +1,46 @@
+use std::fmt;
+use crate::cartridge::Cartridge;
+use crate::dma::Dma;
+use crate::joypad::{Button, Joypad};
+use crate::ppu::{Ppu, Renderer};
+use crate::serial::Serial;
+use crate::timer::Timer;
+
 pub struct  CPU {
     pub registers: Registers,
+    pub pc: u16,
+    pub sp: u16,
+    pub bus: MemoryBus,
+    pub ime: bool,
+    pub ime_scheduled: bool,
+    pub state: CPUState,
+    pub halt_bug: bool,
+    // Machine cycles run since power on
+    pub cycles: u64,
+}
+
+#[derive(Copy,Clone,PartialEq)]
+pub enum CPUState {
+    Running,
+    Halted,
+    Stopped,
+}
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum Model {
+    DMG,
+    CGB,
+}
+
+impl Model {
+    // The two boot ROMs are told apart by size
+    pub fn from_boot_rom(boot_rom: &[u8]) -> Option<Model> {
+        match boot_rom.len() {
+            DMG_BOOT_ROM_SIZE => Some(Model::DMG),
+            CGB_BOOT_ROM_SIZE => Some(Model::CGB),
+            _ => None,
+        }
+    }
 }
 
 // Registers
@@ -94,10 +135,325 @@ impl From<u8> for FlagsRegister {
 }
 // Registers
 
+// Memory
+const ROM_BEGIN: usize = 0x0000;
+const ROM_END: usize = 0x7FFF;
+
+const VRAM_BEGIN: usize = 0x8000;
+const VRAM_END: usize = 0x9FFF;
+
+const EXTERNAL_RAM_BEGIN: usize = 0xA000;
+const EXTERNAL_RAM_END: usize = 0xBFFF;
+
+const WORKING_RAM_BEGIN: usize = 0xC000;
+const WORKING_RAM_END: usize = 0xDFFF;
+const WORKING_RAM_SIZE: usize = WORKING_RAM_END - WORKING_RAM_BEGIN + 1;
+
+const ECHO_RAM_BEGIN: usize = 0xE000;
+const ECHO_RAM_END: usize = 0xFDFF;
+
+const OAM_BEGIN: usize = 0xFE00;
+const OAM_END: usize = 0xFE9F;
+
+const UNUSED_BEGIN: usize = 0xFEA0;
+const UNUSED_END: usize = 0xFEFF;
+
+const IO_REGISTERS_BEGIN: usize = 0xFF00;
+const IO_REGISTERS_END: usize = 0xFF7F;
+const IO_REGISTERS_SIZE: usize = IO_REGISTERS_END - IO_REGISTERS_BEGIN + 1;
+
+const HIGH_RAM_BEGIN: usize = 0xFF80;
+const HIGH_RAM_END: usize = 0xFFFE;
+const HIGH_RAM_SIZE: usize = HIGH_RAM_END - HIGH_RAM_BEGIN + 1;
+
+const JOYPAD_REGISTER: usize = 0xFF00;
+const SERIAL_BEGIN: usize = 0xFF01;
+const SERIAL_END: usize = 0xFF02;
+const TIMER_BEGIN: usize = 0xFF04;
+const TIMER_END: usize = 0xFF07;
+const LCD_BEGIN: usize = 0xFF40;
+const LCD_END: usize = 0xFF4B;
+const DMA_REGISTER: usize = 0xFF46;
+
+const INTERRUPT_FLAG_REGISTER: usize = 0xFF0F;
+const BOOT_ROM_REGISTER: usize = 0xFF50;
+
+// The DMG boot ROM covers 0x0000-0x00FF, the CGB one also 0x0200-0x08FF around the cartridge header
+const DMG_BOOT_ROM_SIZE: usize = 0x100;
+const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+// I/O registers as the boot ROM leaves them on both models
+// The DMA register is left at 0xFF too, see apply_post_boot_state
+const POST_BOOT_IO_REGISTERS: [(u16, u8); 30] = [
+    (0xFF00, 0xCF), (0xFF01, 0x00), (0xFF05, 0x00), (0xFF06, 0x00), (0xFF07, 0xF8),
+    (0xFF0F, 0xE1), (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF),
+    (0xFF14, 0xBF), (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF), (0xFF19, 0xBF),
+    (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF), (0xFF1E, 0xBF),
+    (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00), (0xFF23, 0xBF), (0xFF24, 0x77),
+    (0xFF25, 0xF3), (0xFF26, 0xF1), (0xFF40, 0x91), (0xFF41, 0x85), (0xFF47, 0xFC),
+];
+const INTERRUPT_ENABLE_REGISTER: usize = 0xFFFF;
+
+pub struct MemoryBus {
+    // Overlays the start of the cartridge until the boot ROM writes to 0xFF50
+    pub boot_rom: Option<Vec<u8>>,
+    pub cartridge: Option<Cartridge>,
+    pub working_ram: [u8; WORKING_RAM_SIZE],
+    pub io_registers: [u8; IO_REGISTERS_SIZE],
+    pub high_ram: [u8; HIGH_RAM_SIZE],
+    pub interrupt_enable: u8,
+    pub interrupt_flag: u8,
+    pub timer: Timer,
+    pub serial: Serial,
+    pub ppu: Ppu,
+    pub dma: Dma,
+    pub joypad: Joypad,
+}
+impl MemoryBus {
+    pub fn new(renderer: Renderer) -> MemoryBus {
+        MemoryBus {
+            boot_rom: None,
+            cartridge: None,
+            working_ram: [0; WORKING_RAM_SIZE],
+            io_registers: [0; IO_REGISTERS_SIZE],
+            high_ram: [0; HIGH_RAM_SIZE],
+            interrupt_enable: 0,
+            interrupt_flag: 0,
+            timer: Timer::new(),
+            serial: Serial::new(),
+            ppu: Ppu::new(renderer),
+            dma: Dma::new(),
+            joypad: Joypad::new(),
+        }
+    }
+
+    pub fn load_cartridge(&mut self, cartridge: Cartridge) {
+        self.cartridge = Some(cartridge);
+    }
+
+    pub fn load_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+    }
+
+    fn read_boot_rom(&self, address: usize) -> Option<u8> {
+        match address {
+            0x0000..=0x00FF | 0x0200..=0x08FF => self.boot_rom.as_ref()?.get(address).copied(),
+            _ => None,
+        }
+    }
+
+    // Puts the I/O registers where the boot ROM would have left them
+    pub fn apply_post_boot_state(&mut self, model: Model) {
+        for &(address, value) in POST_BOOT_IO_REGISTERS.iter() {
+            self.write_byte(address, value);
+        }
+        match model {
+            Model::DMG => {
+                self.write_byte(0xFF02, 0x7E);
+                self.timer.div = 0xABCC;
+            }
+            Model::CGB => self.write_byte(0xFF02, 0x7F),
+        }
+        // Set directly, writing the register would start a transfer
+        self.dma.register = 0xFF;
+        self.interrupt_enable = 0x00;
+    }
+
+    // While OAM DMA runs it owns the main bus, so the CPU only reaches the I/O registers and HRAM.
+    // Everything else reads as 0xFF and ignores writes.
+    fn dma_blocks(&self, address: u16) -> bool {
+        self.dma.is_active() && (address as usize) < IO_REGISTERS_BEGIN
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        if self.dma_blocks(address) {
+            return 0xFF;
+        }
+        self.read_memory(address)
+    }
+
+    fn read_memory(&self, address: u16) -> u8 {
+        let address = address as usize;
+        match address {
+            // With no cartridge inserted the data lines float high
+            ROM_BEGIN..=ROM_END => match self.read_boot_rom(address) {
+                Some(byte) => byte,
+                None => self.cartridge.as_ref().map_or(0xFF, |cartridge| cartridge.read_rom(address as u16)),
+            },
+            VRAM_BEGIN..=VRAM_END => self.ppu.read_byte(address as u16),
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END => {
+                self.cartridge.as_ref().map_or(0xFF, |cartridge| cartridge.read_ram(address as u16))
+            }
+            WORKING_RAM_BEGIN..=WORKING_RAM_END => self.working_ram[address - WORKING_RAM_BEGIN],
+            // Echo RAM mirrors the first 0x1E00 bytes of working RAM
+            ECHO_RAM_BEGIN..=ECHO_RAM_END => self.working_ram[address - ECHO_RAM_BEGIN],
+            OAM_BEGIN..=OAM_END => self.ppu.read_byte(address as u16),
+            UNUSED_BEGIN..=UNUSED_END => 0xFF,
+            IO_REGISTERS_BEGIN..=IO_REGISTERS_END => self.read_io_register(address),
+            HIGH_RAM_BEGIN..=HIGH_RAM_END => self.high_ram[address - HIGH_RAM_BEGIN],
+            INTERRUPT_ENABLE_REGISTER => self.interrupt_enable,
+            _ => unreachable!(),
+        }
+    }
+
+    // Registers without a component behind them yet read back whatever was last written
+    fn read_io_register(&self, address: usize) -> u8 {
+        match address {
+            JOYPAD_REGISTER => self.joypad.read_byte(),
+            SERIAL_BEGIN..=SERIAL_END => self.serial.read_byte(address as u16),
+            TIMER_BEGIN..=TIMER_END => self.timer.read_byte(address as u16),
+            // The DMA register sits in the middle of the LCD registers but is not part of the PPU
+            DMA_REGISTER => self.dma.register,
+            LCD_BEGIN..=LCD_END => self.ppu.read_byte(address as u16),
+            // The top three bits of IF are unused and always read as 1
+            INTERRUPT_FLAG_REGISTER => self.interrupt_flag | 0xE0,
+            BOOT_ROM_REGISTER => 0xFF,
+            _ => self.io_registers[address - IO_REGISTERS_BEGIN],
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        if self.dma_blocks(address) {
+            return;
+        }
+        let address = address as usize;
+        match address {
+            ROM_BEGIN..=ROM_END => {
+                if let Some(cartridge) = self.cartridge.as_mut() {
+                    cartridge.write_rom(address as u16, value);
+                }
+            }
+            VRAM_BEGIN..=VRAM_END => self.ppu.write_byte(address as u16, value),
+            EXTERNAL_RAM_BEGIN..=EXTERNAL_RAM_END => {
+                if let Some(cartridge) = self.cartridge.as_mut() {
+                    cartridge.write_ram(address as u16, value);
+                }
+            }
+            WORKING_RAM_BEGIN..=WORKING_RAM_END => self.working_ram[address - WORKING_RAM_BEGIN] = value,
+            ECHO_RAM_BEGIN..=ECHO_RAM_END => self.working_ram[address - ECHO_RAM_BEGIN] = value,
+            OAM_BEGIN..=OAM_END => self.ppu.write_byte(address as u16, value),
+            UNUSED_BEGIN..=UNUSED_END => {}
+            IO_REGISTERS_BEGIN..=IO_REGISTERS_END => self.write_io_register(address, value),
+            HIGH_RAM_BEGIN..=HIGH_RAM_END => self.high_ram[address - HIGH_RAM_BEGIN] = value,
+            INTERRUPT_ENABLE_REGISTER => self.interrupt_enable = value,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_io_register(&mut self, address: usize, value: u8) {
+        match address {
+            JOYPAD_REGISTER => {
+                if self.joypad.write_byte(value) {
+                    self.request_interrupt(Interrupt::Joypad);
+                }
+            }
+            SERIAL_BEGIN..=SERIAL_END => self.serial.write_byte(address as u16, value),
+            TIMER_BEGIN..=TIMER_END => self.timer.write_byte(address as u16, value),
+            DMA_REGISTER => self.dma.start(value),
+            LCD_BEGIN..=LCD_END => self.ppu.write_byte(address as u16, value),
+            INTERRUPT_FLAG_REGISTER => self.interrupt_flag = value & 0x1F,
+            // Any non-zero write unmaps the boot ROM until the next power cycle
+            BOOT_ROM_REGISTER => {
+                if value != 0 {
+                    self.boot_rom = None;
+                }
+            }
+            _ => self.io_registers[address - IO_REGISTERS_BEGIN] = value,
+        }
+    }
+
+    // Advances the components hanging off the bus by a number of machine cycles
+    pub fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            if self.timer.tick() {
+                self.request_interrupt(Interrupt::Timer);
+            }
+            if self.serial.tick() {
+                self.request_interrupt(Interrupt::Serial);
+            }
+            self.interrupt_flag |= self.ppu.tick();
+            if let Some((source, index)) = self.dma.tick() {
+                // DMA reads VRAM and writes OAM regardless of what the PPU is doing
+                let value = match source as usize {
+                    VRAM_BEGIN..=VRAM_END => self.ppu.vram[source as usize - VRAM_BEGIN],
+                    _ => self.read_memory(source),
+                };
+                self.ppu.oam[index] = value;
+            }
+        }
+    }
+
+    // Input from the frontend
+    pub fn press(&mut self, button: Button) {
+        if self.joypad.press(button) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn release(&mut self, button: Button) {
+        if self.joypad.release(button) {
+            self.request_interrupt(Interrupt::Joypad);
+        }
+    }
+
+    pub fn request_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt_flag |= interrupt.mask();
+    }
+
+    // Interrupts that are both requested in IF and enabled in IE
+    pub fn pending_interrupts(&self) -> u8 {
+        self.interrupt_enable & self.interrupt_flag & 0x1F
+    }
+}
+// Memory
+
+// Interrupts
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum Interrupt {
+    VBlank,
+    LCDStat,
+    Timer,
+    Serial,
+    Joypad,
+}
+
+// Ordered by priority, VBlank is serviced first
+const INTERRUPTS: [Interrupt; 5] = [
+    Interrupt::VBlank,
+    Interrupt::LCDStat,
+    Interrupt::Timer,
+    Interrupt::Serial,
+    Interrupt::Joypad,
+];
+
+impl Interrupt {
+    pub fn mask(&self) -> u8 {
+        match self {
+            Interrupt::VBlank => 1 << 0,
+            Interrupt::LCDStat => 1 << 1,
+            Interrupt::Timer => 1 << 2,
+            Interrupt::Serial => 1 << 3,
+            Interrupt::Joypad => 1 << 4,
+        }
+    }
+
+    fn vector(&self) -> u16 {
+        match self {
+            Interrupt::VBlank => 0x40,
+            Interrupt::LCDStat => 0x48,
+            Interrupt::Timer => 0x50,
+            Interrupt::Serial => 0x58,
+            Interrupt::Joypad => 0x60,
+        }
+    }
+}
+// Interrupts
+
 // Instructions
 pub enum Instruction {
     ADD(ArithmeticTarget),
-    ADDHL(ArithmeticTarget),
+    ADDHL(WordTarget),
+    ADDSP(),
     ADC(ArithmeticTarget),
     SUB(ArithmeticTarget),
     SBC(ArithmeticTarget),
@@ -107,12 +463,14 @@ pub enum Instruction {
     CP(ArithmeticTarget),
     INC(ArithmeticTarget),
     DEC(ArithmeticTarget),
+    INC16(WordTarget),
+    DEC16(WordTarget),
     CCF(),
     SCF(),
     RRA(),
     RLA(),
     RRCA(),
-    RRLA(), // Possibly RCLA?
+    RLCA(),
     CPL(),
     BIT(ArithmeticTarget, u8),
     RES(ArithmeticTarget, u8),
@@ -126,396 +484,552 @@ pub enum Instruction {
     SRA(ArithmeticTarget),
     SLA(ArithmeticTarget),
     SWAP(ArithmeticTarget),
+    LD(LoadType),
+    JP(JumpTest),
+    JPHL(),
+    JR(JumpTest),
+    CALL(JumpTest),
+    RET(JumpTest),
+    RETI(),
+    RST(u8),
+    PUSH(StackTarget),
+    POP(StackTarget),
+    DAA(),
+    NOP(),
+    HALT(),
+    STOP(),
+    DI(),
+    EI(),
+}
+
+#[derive(Copy,Clone)]
+pub enum WordTarget {
+    BC, DE, HL, SP,
 }
 
+pub enum StackTarget {
+    BC, DE, HL, AF,
+}
+
+pub enum JumpTest {
+    NotZero,
+    Zero,
+    NotCarry,
+    Carry,
+    Always,
+}
+
+#[derive(Copy,Clone)]
 pub enum ArithmeticTarget {
-    A, B, C, D, E, H, L,
+    A, B, C, D, E, H, L, HLI, D8,
+}
+
+pub enum LoadByteTarget {
+    A, B, C, D, E, H, L, HLI,
+}
+
+pub enum LoadByteSource {
+    A, B, C, D, E, H, L, D8, HLI,
+}
+
+pub enum LoadWordTarget {
+    BC, DE, HL, SP,
+}
+
+pub enum Indirect {
+    BC,
+    DE,
+    HLPlus,
+    HLMinus,
+    Word,
+    LastByte,
+}
+
+pub enum LoadType {
+    Byte(LoadByteTarget, LoadByteSource),
+    Word(LoadWordTarget),
+    AFromIndirect(Indirect),
+    IndirectFromA(Indirect),
+    AFromByteAddress,
+    ByteAddressFromA,
+    SPFromHL,
+    IndirectFromSP,
+    HLFromSPN,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    // One of the 11 opcodes with no instruction behind them; real hardware locks up on these
+    IllegalOpcode(u8),
+    // 0xCB is only a prefix, the opcode that follows it selects the instruction
+    PrefixWithoutOpcode,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::IllegalOpcode(byte) => write!(f, "illegal opcode {:#04x}", byte),
+            DecodeError::PrefixWithoutOpcode => write!(f, "0xCB prefix decoded without its opcode"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl Instruction {
+    pub fn from_byte(byte: u8, prefixed: bool) -> Result<Instruction, DecodeError> {
+        if prefixed {
+            Instruction::from_byte_prefixed(byte)
+        } else {
+            Instruction::from_byte_not_prefixed(byte)
+        }
+    }
+
+    fn from_byte_prefixed(byte: u8) -> Result<Instruction, DecodeError> {
+        let target = ArithmeticTarget::from_bits(byte);
+        let bit = (byte >> 3) & 0b111;
+        match byte {
+            0x00..=0x07 => Ok(Instruction::RLC(target)),
+            0x08..=0x0F => Ok(Instruction::RRC(target)),
+            0x10..=0x17 => Ok(Instruction::RL(target)),
+            0x18..=0x1F => Ok(Instruction::RR(target)),
+            0x20..=0x27 => Ok(Instruction::SLA(target)),
+            0x28..=0x2F => Ok(Instruction::SRA(target)),
+            0x30..=0x37 => Ok(Instruction::SWAP(target)),
+            0x38..=0x3F => Ok(Instruction::SRL(target)),
+            0x40..=0x7F => Ok(Instruction::BIT(target, bit)),
+            0x80..=0xBF => Ok(Instruction::RES(target, bit)),
+            0xC0..=0xFF => Ok(Instruction::SET(target, bit)),
+        }
+    }
+
+    fn from_byte_not_prefixed(byte: u8) -> Result<Instruction, DecodeError> {
+        match byte {
+            0x00 => Ok(Instruction::NOP()),
+            0x10 => Ok(Instruction::STOP()),
+            0x27 => Ok(Instruction::DAA()),
+            0x76 => Ok(Instruction::HALT()),
+            0xF3 => Ok(Instruction::DI()),
+            0xFB => Ok(Instruction::EI()),
+            0x07 => Ok(Instruction::RLCA()),
+            0x0F => Ok(Instruction::RRCA()),
+            0x17 => Ok(Instruction::RLA()),
+            0x1F => Ok(Instruction::RRA()),
+            0x2F => Ok(Instruction::CPL()),
+            0x37 => Ok(Instruction::SCF()),
+            0x3F => Ok(Instruction::CCF()),
+            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => {
+                Ok(Instruction::INC(ArithmeticTarget::from_bits(byte >> 3)))
+            }
+            0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => {
+                Ok(Instruction::DEC(ArithmeticTarget::from_bits(byte >> 3)))
+            }
+            0x80..=0xBF => {
+                let target = ArithmeticTarget::from_bits(byte);
+                match byte {
+                    0x80..=0x87 => Ok(Instruction::ADD(target)),
+                    0x88..=0x8F => Ok(Instruction::ADC(target)),
+                    0x90..=0x97 => Ok(Instruction::SUB(target)),
+                    0x98..=0x9F => Ok(Instruction::SBC(target)),
+                    0xA0..=0xA7 => Ok(Instruction::AND(target)),
+                    0xA8..=0xAF => Ok(Instruction::XOR(target)),
+                    0xB0..=0xB7 => Ok(Instruction::OR(target)),
+                    _ => Ok(Instruction::CP(target)),
+                }
+            }
+            0x09 | 0x19 | 0x29 | 0x39 => Ok(Instruction::ADDHL(WordTarget::from_bits(byte >> 4))),
+            0x03 | 0x13 | 0x23 | 0x33 => Ok(Instruction::INC16(WordTarget::from_bits(byte >> 4))),
+            0x0B | 0x1B | 0x2B | 0x3B => Ok(Instruction::DEC16(WordTarget::from_bits(byte >> 4))),
+            0xE8 => Ok(Instruction::ADDSP()),
+            0xC6 => Ok(Instruction::ADD(ArithmeticTarget::D8)),
+            0xCE => Ok(Instruction::ADC(ArithmeticTarget::D8)),
+            0xD6 => Ok(Instruction::SUB(ArithmeticTarget::D8)),
+            0xDE => Ok(Instruction::SBC(ArithmeticTarget::D8)),
+            0xE6 => Ok(Instruction::AND(ArithmeticTarget::D8)),
+            0xEE => Ok(Instruction::XOR(ArithmeticTarget::D8)),
+            0xF6 => Ok(Instruction::OR(ArithmeticTarget::D8)),
+            0xFE => Ok(Instruction::CP(ArithmeticTarget::D8)),
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => {
+                Ok(Instruction::LD(LoadType::Byte(LoadByteTarget::from_bits(byte >> 3), LoadByteSource::D8)))
+            }
+            // 0x76 sits where LD (HL),(HL) would be
+            0x40..=0x75 | 0x77..=0x7F => {
+                Ok(Instruction::LD(LoadType::Byte(LoadByteTarget::from_bits(byte >> 3), LoadByteSource::from_bits(byte))))
+            }
+            0x01 => Ok(Instruction::LD(LoadType::Word(LoadWordTarget::BC))),
+            0x11 => Ok(Instruction::LD(LoadType::Word(LoadWordTarget::DE))),
+            0x21 => Ok(Instruction::LD(LoadType::Word(LoadWordTarget::HL))),
+            0x31 => Ok(Instruction::LD(LoadType::Word(LoadWordTarget::SP))),
+            0x02 => Ok(Instruction::LD(LoadType::IndirectFromA(Indirect::BC))),
+            0x12 => Ok(Instruction::LD(LoadType::IndirectFromA(Indirect::DE))),
+            0x22 => Ok(Instruction::LD(LoadType::IndirectFromA(Indirect::HLPlus))),
+            0x32 => Ok(Instruction::LD(LoadType::IndirectFromA(Indirect::HLMinus))),
+            0x0A => Ok(Instruction::LD(LoadType::AFromIndirect(Indirect::BC))),
+            0x1A => Ok(Instruction::LD(LoadType::AFromIndirect(Indirect::DE))),
+            0x2A => Ok(Instruction::LD(LoadType::AFromIndirect(Indirect::HLPlus))),
+            0x3A => Ok(Instruction::LD(LoadType::AFromIndirect(Indirect::HLMinus))),
+            0xE0 => Ok(Instruction::LD(LoadType::ByteAddressFromA)),
+            0xF0 => Ok(Instruction::LD(LoadType::AFromByteAddress)),
+            0xE2 => Ok(Instruction::LD(LoadType::IndirectFromA(Indirect::LastByte))),
+            0xF2 => Ok(Instruction::LD(LoadType::AFromIndirect(Indirect::LastByte))),
+            0xEA => Ok(Instruction::LD(LoadType::IndirectFromA(Indirect::Word))),
+            0xFA => Ok(Instruction::LD(LoadType::AFromIndirect(Indirect::Word))),
+            0x08 => Ok(Instruction::LD(LoadType::IndirectFromSP)),
+            0xF8 => Ok(Instruction::LD(LoadType::HLFromSPN)),
+            0xF9 => Ok(Instruction::LD(LoadType::SPFromHL)),
+            0xC3 => Ok(Instruction::JP(JumpTest::Always)),
+            0xC2 | 0xCA | 0xD2 | 0xDA => Ok(Instruction::JP(JumpTest::from_bits(byte >> 3))),
+            0xE9 => Ok(Instruction::JPHL()),
+            0x18 => Ok(Instruction::JR(JumpTest::Always)),
+            0x20 | 0x28 | 0x30 | 0x38 => Ok(Instruction::JR(JumpTest::from_bits(byte >> 3))),
+            0xCD => Ok(Instruction::CALL(JumpTest::Always)),
+            0xC4 | 0xCC | 0xD4 | 0xDC => Ok(Instruction::CALL(JumpTest::from_bits(byte >> 3))),
+            0xC9 => Ok(Instruction::RET(JumpTest::Always)),
+            0xC0 | 0xC8 | 0xD0 | 0xD8 => Ok(Instruction::RET(JumpTest::from_bits(byte >> 3))),
+            0xD9 => Ok(Instruction::RETI()),
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => Ok(Instruction::RST(byte & 0x38)),
+            0xC5 | 0xD5 | 0xE5 | 0xF5 => Ok(Instruction::PUSH(StackTarget::from_bits(byte >> 4))),
+            0xC1 | 0xD1 | 0xE1 | 0xF1 => Ok(Instruction::POP(StackTarget::from_bits(byte >> 4))),
+            0xCB => Err(DecodeError::PrefixWithoutOpcode),
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+                Err(DecodeError::IllegalOpcode(byte))
+            }
+        }
+    }
+}
+
+impl ArithmeticTarget {
+    // Register operands are encoded in the low three bits of an opcode as B, C, D, E, H, L, (HL), A
+    fn from_bits(bits: u8) -> ArithmeticTarget {
+        match bits & 0b111 {
+            0 => ArithmeticTarget::B,
+            1 => ArithmeticTarget::C,
+            2 => ArithmeticTarget::D,
+            3 => ArithmeticTarget::E,
+            4 => ArithmeticTarget::H,
+            5 => ArithmeticTarget::L,
+            6 => ArithmeticTarget::HLI,
+            _ => ArithmeticTarget::A,
+        }
+    }
+}
+
+impl JumpTest {
+    // Conditions are encoded in bits 3-4 of an opcode as NZ, Z, NC, C
+    fn from_bits(bits: u8) -> JumpTest {
+        match bits & 0b11 {
+            0 => JumpTest::NotZero,
+            1 => JumpTest::Zero,
+            2 => JumpTest::NotCarry,
+            _ => JumpTest::Carry,
+        }
+    }
+}
+
+impl WordTarget {
+    // Register pairs are encoded in bits 4-5 of an opcode as BC, DE, HL, SP
+    fn from_bits(bits: u8) -> WordTarget {
+        match bits & 0b11 {
+            0 => WordTarget::BC,
+            1 => WordTarget::DE,
+            2 => WordTarget::HL,
+            _ => WordTarget::SP,
+        }
+    }
+}
+
+impl StackTarget {
+    // Register pairs are encoded in bits 4-5 of an opcode as BC, DE, HL, AF
+    fn from_bits(bits: u8) -> StackTarget {
+        match bits & 0b11 {
+            0 => StackTarget::BC,
+            1 => StackTarget::DE,
+            2 => StackTarget::HL,
+            _ => StackTarget::AF,
+        }
+    }
+}
+
+impl LoadByteTarget {
+    fn from_bits(bits: u8) -> LoadByteTarget {
+        match bits & 0b111 {
+            0 => LoadByteTarget::B,
+            1 => LoadByteTarget::C,
+            2 => LoadByteTarget::D,
+            3 => LoadByteTarget::E,
+            4 => LoadByteTarget::H,
+            5 => LoadByteTarget::L,
+            6 => LoadByteTarget::HLI,
+            _ => LoadByteTarget::A,
+        }
+    }
+}
+
+impl LoadByteSource {
+    fn from_bits(bits: u8) -> LoadByteSource {
+        match bits & 0b111 {
+            0 => LoadByteSource::B,
+            1 => LoadByteSource::C,
+            2 => LoadByteSource::D,
+            3 => LoadByteSource::E,
+            4 => LoadByteSource::H,
+            5 => LoadByteSource::L,
+            6 => LoadByteSource::HLI,
+            _ => LoadByteSource::A,
+        }
+    }
 }
 
 impl CPU {
+    // Powers on at 0x0000, ready to run a boot ROM
+    pub fn new(bus: MemoryBus) -> CPU {
+        CPU {
+            registers: Registers {
+                a: 0,
+                b: 0,
+                c: 0,
+                d: 0,
+                e: 0,
+                f: FlagsRegister::from(0),
+                h: 0,
+                l: 0,
+            },
+            pc: 0x0000,
+            sp: 0x0000,
+            bus,
+            ime: false,
+            ime_scheduled: false,
+            state: CPUState::Running,
+            halt_bug: false,
+            cycles: 0,
+        }
+    }
+
+    // Starts at the cartridge entry point with the state the boot ROM hands over
+    pub fn skip_boot_rom(&mut self, model: Model) {
+        match model {
+            Model::DMG => {
+                // Half carry and carry stay set unless the header checksum happens to be zero
+                let header_checksum = self.bus.read_byte(0x014D);
+                self.registers.set_af(if header_checksum == 0 { 0x0180 } else { 0x01B0 });
+                self.registers.set_bc(0x0013);
+                self.registers.set_de(0x00D8);
+                self.registers.set_hl(0x014D);
+            }
+            Model::CGB => {
+                self.registers.set_af(0x1180);
+                self.registers.set_bc(0x0000);
+                self.registers.set_de(0xFF56);
+                self.registers.set_hl(0x000D);
+            }
+        }
+        self.sp = 0xFFFE;
+        self.pc = 0x0100;
+        self.bus.boot_rom = None;
+        self.bus.apply_post_boot_state(model);
+    }
+
     pub fn print(&self){
         println!("a: {:#010b} b: {:#010b}",self.registers.a,self.registers.b);
         println!("c: {:#010b} d: {:#010b}",self.registers.c,self.registers.d);
         println!("e: {:#010b} f: {:#010b}",self.registers.e,u8::from(self.registers.f));
         println!("h: {:#010b} l: {:#010b}",self.registers.h,self.registers.l);
+        println!("pc: {:#06x} sp: {:#06x}",self.pc,self.sp);
+
+    }
+    // Runs one instruction, interrupt dispatch or idle cycle and returns the machine cycles it took.
+    // The rest of the hardware is ticked as the CPU goes, so it sees every access at the right time.
+    pub fn step(&mut self) -> Result<u8, DecodeError> {
+        let start = self.cycles;
+        self.run_next()?;
+        Ok((self.cycles - start) as u8)
+    }
+
+    fn run_next(&mut self) -> Result<(), DecodeError> {
+        if self.handle_interrupts() {
+            return Ok(());
+        }
+        // STOP lasts until a selected button pulls a joypad line low
+        if self.state == CPUState::Stopped && self.bus.joypad.any_line_low() {
+            self.state = CPUState::Running;
+        }
+        if self.state != CPUState::Running {
+            self.tick();
+            return Ok(());
+        }
+
+        // EI only takes effect once the instruction after it has finished
+        let enable_interrupts = self.ime_scheduled;
+
+        let mut instruction_byte = if self.halt_bug {
+            // The HALT bug fetches the byte after HALT without advancing PC, so it runs twice
+            self.halt_bug = false;
+            self.read_byte(self.pc)
+        } else {
+            self.read_next_byte()
+        };
+        let prefixed = instruction_byte == 0xCB;
+        if prefixed {
+            instruction_byte = self.read_next_byte();
+        }
+
+        let instruction = Instruction::from_byte(instruction_byte, prefixed)?;
+        self.execute(instruction);
+
+        if enable_interrupts && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+        Ok(())
+    }
+
+    // Wakes the CPU from HALT on any pending interrupt and, with IME set, jumps to the
+    // highest priority one. Returns true if an interrupt was dispatched.
+    fn handle_interrupts(&mut self) -> bool {
+        let pending = self.bus.pending_interrupts();
+        if pending == 0 {
+            return false;
+        }
+        if self.state == CPUState::Halted {
+            self.state = CPUState::Running;
+        }
+        if !self.ime {
+            return false;
+        }
+
+        let interrupt = match INTERRUPTS.iter().find(|interrupt| pending & interrupt.mask() != 0) {
+            Some(interrupt) => *interrupt,
+            None => return false,
+        };
+        self.ime = false;
+        self.ime_scheduled = false;
+        self.bus.interrupt_flag &= !interrupt.mask();
+        // EI right before HALT with an interrupt already pending trips the HALT bug, but the
+        // dispatch comes first and returns to the HALT itself instead
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
+        }
+        // Two idle cycles, the PC push, then one more to load the vector
+        self.tick();
+        self.tick();
+        self.push(self.pc);
+        self.tick();
+        self.pc = interrupt.vector();
+        true
+    }
 
+    // Advances the rest of the hardware by one machine cycle
+    fn tick(&mut self) {
+        self.bus.tick(1);
+        self.cycles += 1;
     }
-    pub fn execute(&mut self, instruction: Instruction) {
+
+    // Every memory access by the CPU takes one machine cycle
+    fn read_byte(&mut self, address: u16) -> u8 {
+        self.tick();
+        self.bus.read_byte(address)
+    }
+
+    fn write_byte(&mut self, address: u16, value: u8) {
+        self.tick();
+        self.bus.write_byte(address, value);
+    }
+
+    fn read_next_byte(&mut self) -> u8 {
+        let byte = self.read_byte(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        byte
+    }
+
+    fn read_next_word(&mut self) -> u16 {
+        let low = self.read_next_byte() as u16;
+        let high = self.read_next_byte() as u16;
+        (high << 8) | low
+    }
+
+    // Only reached through step, with instructions from the decoder. Those never write to D8.
+    fn execute(&mut self, instruction: Instruction) {
         match instruction {
             Instruction::ADD(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        self.registers.a = self.add(value);
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        self.registers.a = self.add(value);
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        self.registers.a = self.add(value);
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        self.registers.a = self.add(value);
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        self.registers.a = self.add(value);
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        self.registers.a = self.add(value);
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        self.registers.a = self.add(value);
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.add(value, false);
             },
             Instruction::ADDHL(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        let new_value = self.addhl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        let new_value = self.addhl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        let new_value = self.addhl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        let new_value = self.addhl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        let new_value = self.addhl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        let new_value = self.addhl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        let new_value = self.addhl(value);
-                        self.registers.set_hl(new_value);
-                    }
-                }
+                let value = self.read_word_target(target);
+                let new_value = self.addhl(value);
+                self.registers.set_hl(new_value);
+                self.tick();
+            },
+            Instruction::ADDSP() => {
+                let offset = self.read_next_byte();
+                self.sp = self.add_sp_signed(offset);
+                self.tick();
+                self.tick();
             },
             Instruction::ADC(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        self.registers.a = self.add(value);
-                        self.registers.a = self.add(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        self.registers.a = self.add(value);
-                        self.registers.a = self.add(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        self.registers.a = self.add(value);
-                        self.registers.a = self.add(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        self.registers.a = self.add(value);
-                        self.registers.a = self.add(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        self.registers.a = self.add(value);
-                        self.registers.a = self.add(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        self.registers.a = self.add(value);
-                        self.registers.a = self.add(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        self.registers.a = self.add(value);
-                        self.registers.a = self.add(u8::from(self.registers.f.carry));
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.add(value, self.registers.f.carry);
             },
             Instruction::SUB(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        self.registers.a = self.sub(value);
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        self.registers.a = self.sub(value);
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        self.registers.a = self.sub(value);
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        self.registers.a = self.sub(value);
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        self.registers.a = self.sub(value);
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        self.registers.a = self.sub(value);
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        self.registers.a = self.sub(value);
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.sub(value, false);
             },
             Instruction::SBC(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let value = self.registers.a;
-                        self.registers.a = self.sub(value);
-                        self.registers.a = self.sub(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::B => {
-                        let value = self.registers.b;
-                        self.registers.a = self.sub(value);
-                        self.registers.a = self.sub(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::C => {
-                        let value = self.registers.c;
-                        self.registers.a = self.sub(value);
-                        self.registers.a = self.sub(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::D => {
-                        let value = self.registers.d;
-                        self.registers.a = self.sub(value);
-                        self.registers.a = self.sub(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::E => {
-                        let value = self.registers.e;
-                        self.registers.a = self.sub(value);
-                        self.registers.a = self.sub(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::H => {
-                        let value = self.registers.h;
-                        self.registers.a = self.sub(value);
-                        self.registers.a = self.sub(u8::from(self.registers.f.carry));
-                    }
-                    ArithmeticTarget::L => {
-                        let value = self.registers.l;
-                        self.registers.a = self.sub(value);
-                        self.registers.a = self.sub(u8::from(self.registers.f.carry));
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.a = self.sub(value, self.registers.f.carry);
             },
             Instruction::AND(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.a &= self.registers.b;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.a &= self.registers.c;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.a &= self.registers.d;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.a &= self.registers.e;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.a &= self.registers.h;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.a &= self.registers.l;
-                    }
-                }
+                self.registers.a &= self.read_target(target);
                 self.registers.f.zero = self.registers.a == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = true;
                 self.registers.f.carry = false;
             },
             Instruction::OR(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.a |= self.registers.a;
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.a |= self.registers.b;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.a |= self.registers.c;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.a |= self.registers.d;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.a |= self.registers.e;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.a |= self.registers.h;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.a |= self.registers.l;
-                    }
-                }
+                self.registers.a |= self.read_target(target);
                 self.registers.f.zero = self.registers.a == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
                 self.registers.f.carry = false;
             },
             Instruction::XOR(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.a ^= self.registers.a;
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.a ^= self.registers.b;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.a ^= self.registers.c;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.a ^= self.registers.d;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.a ^= self.registers.e;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.a ^= self.registers.h;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.a ^= self.registers.l;
-                    }
-                }
+                self.registers.a ^= self.read_target(target);
                 self.registers.f.zero = self.registers.a == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
                 self.registers.f.carry = false;
             },
             Instruction::CP(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.f.zero = self.registers.a == self.registers.a;
-                        self.registers.f.carry = false;
-                        self.registers.f.subtract = (self.registers.a ^ self.registers.a ^ self.sub(self.registers.l)) & 0x10 != 0;
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.f.zero = self.registers.a == self.registers.b;
-                        self.registers.f.carry = self.registers.a < self.registers.b;
-                        self.registers.f.subtract = (self.registers.a ^ self.registers.b ^ self.sub(self.registers.l)) & 0x10 != 0;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.f.zero = self.registers.a == self.registers.c;
-                        self.registers.f.carry = self.registers.a < self.registers.c;
-                        self.registers.f.subtract = (self.registers.a ^ self.registers.c ^ self.sub(self.registers.l)) & 0x10 != 0;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.f.zero = self.registers.a == self.registers.d;
-                        self.registers.f.carry = self.registers.a < self.registers.d;
-                        self.registers.f.subtract = (self.registers.a ^ self.registers.d ^ self.sub(self.registers.l)) & 0x10 != 0;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.f.zero = self.registers.a == self.registers.e;
-                        self.registers.f.carry = self.registers.a < self.registers.e;
-                        self.registers.f.subtract = (self.registers.a ^ self.registers.e ^ self.sub(self.registers.l)) & 0x10 != 0;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.f.zero = self.registers.a == self.registers.h;
-                        self.registers.f.carry = self.registers.a < self.registers.h;
-                        self.registers.f.subtract = (self.registers.a ^ self.registers.h ^ self.sub(self.registers.l)) & 0x10 != 0;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.f.zero = self.registers.a == self.registers.l;
-                        self.registers.f.carry = self.registers.a < self.registers.l;
-                        self.registers.f.subtract = (self.registers.a ^ self.registers.l ^ self.sub(self.registers.l)) & 0x10 != 0;
-                    }
-                }
-                self.registers.f.half_carry = false;
+                // CP is a SUB that only keeps the flags
+                let value = self.read_target(target);
+                self.sub(value, false);
             },
             Instruction::INC(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.a = self.add(1);
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.b = self.add(1);
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.c = self.add(1);
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.d = self.add(1);
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.e = self.add(1);
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.h = self.add(1);
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.l = self.add(1);
-                    }
-                }
-                self.registers.f.subtract = true;
-                self.registers.f.half_carry = true;
+                let value = self.read_target(target);
+                let new_value = value.wrapping_add(1);
+                self.registers.f.zero = new_value == 0;
+                self.registers.f.subtract = false;
+                self.registers.f.half_carry = value & 0xF == 0xF;
+                self.write_target(target, new_value);
             },
             Instruction::DEC(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-
-                        self.registers.f.half_carry = self.registers.a & 0xf == 0;
-                        self.registers.a = self.sub(1);
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.f.half_carry = self.registers.b & 0xf == 0;
-                        self.registers.b = self.sub(1);
-                        self.registers.f.zero = self.registers.b == 0;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.f.half_carry = self.registers.c & 0xf == 0;
-                        self.registers.c = self.sub(1);
-                        self.registers.f.zero = self.registers.c == 0;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.f.half_carry = self.registers.d & 0xf == 0;
-                        self.registers.d = self.sub(1);
-                        self.registers.f.zero = self.registers.d == 0;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.f.half_carry = self.registers.e & 0xf == 0;
-                        self.registers.e = self.sub(1);
-                        self.registers.f.zero = self.registers.e == 0;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.f.half_carry = self.registers.h & 0xf == 0;
-                        self.registers.h = self.sub(1);
-                        self.registers.f.zero = self.registers.h == 0;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.f.half_carry = self.registers.l & 0xf == 0;
-                        self.registers.l = self.sub(1);
-                        self.registers.f.zero = self.registers.l == 0;
-                    }
-                }
+                let value = self.read_target(target);
+                let new_value = value.wrapping_sub(1);
+                self.registers.f.zero = new_value == 0;
                 self.registers.f.subtract = true;
+                self.registers.f.half_carry = value & 0xF == 0;
+                self.write_target(target, new_value);
+            },
+            Instruction::INC16(target) => {
+                let value = self.read_word_target(target);
+                self.write_word_target(target, value.wrapping_add(1));
+                self.tick();
+            },
+            Instruction::DEC16(target) => {
+                let value = self.read_word_target(target);
+                self.write_word_target(target, value.wrapping_sub(1));
+                self.tick();
             },
             Instruction::CCF() => {
                 self.registers.f.carry = !self.registers.f.carry;
@@ -559,12 +1073,12 @@ impl CPU {
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
             },
-            Instruction::RRLA() => {
-                let c = self.registers.a >> 1;
+            Instruction::RLCA() => {
+                let c = self.registers.a >> 7;
 
-                self.registers.a = (self.registers.a << 1) | c;
+                self.registers.a = self.registers.a.rotate_left(1);
 
-                self.registers.f.carry = (c != 0);
+                self.registers.f.carry = c != 0;
                 self.registers.f.zero = false;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
@@ -576,486 +1090,663 @@ impl CPU {
                 self.registers.f.half_carry = true;
             },
             Instruction::BIT(target, b) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.f.zero = ((1u8 << b) & self.registers.a) == 0;
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.f.zero = ((1u8 << b) & self.registers.b) == 0;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.f.zero = ((1u8 << b) & self.registers.c) == 0;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.f.zero = ((1u8 << b) & self.registers.d) == 0;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.f.zero = ((1u8 << b) & self.registers.e) == 0;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.f.zero = ((1u8 << b) & self.registers.h) == 0;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.f.zero = ((1u8 << b) & self.registers.l) == 0;
-                    }
-                }
+                let value = self.read_target(target);
+                self.registers.f.zero = ((1u8 << b) & value) == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = true;
             },
             Instruction::RES(target, b) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.a &= !(1u8 << b);
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.b &= !(1u8 << b);
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.c &= !(1u8 << b);
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.d &= !(1u8 << b);
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.e &= !(1u8 << b);
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.h &= !(1u8 << b);
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.l &= !(1u8 << b);
-                    }
-                }
+                let value = self.read_target(target);
+                self.write_target(target, value & !(1u8 << b));
             },
             Instruction::SET(target, b) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.a |= (1u8 << b);
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.b |= (1u8 << b);
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.c |= (1u8 << b);
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.d |= (1u8 << b);
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.e |= (1u8 << b);
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.h |= (1u8 << b);
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.l |= (1u8 << b);
-                    }
-                }
+                let value = self.read_target(target);
+                self.write_target(target, value | (1u8 << b));
             },
             Instruction::SRL(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.a >>= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.b >>= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.c >>= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.d >>= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.e >>= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.h >>= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.l >>= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                }
-                self.registers.f.half_carry = false;
+                let value = self.read_target(target);
+                let new_value = value >> 1;
+                self.registers.f.carry = value & 1 != 0;
+                self.registers.f.zero = new_value == 0;
                 self.registers.f.subtract = false;
+                self.registers.f.half_carry = false;
+                self.write_target(target, new_value);
             },
             Instruction::SLL(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.a <<= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.a <<= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.a <<= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.a <<= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.a <<= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.a <<= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.f.carry = (self.registers.a & 1 != 0);
-                        self.registers.a <<= 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                }
-                self.registers.f.half_carry = false;
+                let value = self.read_target(target);
+                let new_value = value << 1;
+                self.registers.f.carry = value & 0x80 != 0;
+                self.registers.f.zero = new_value == 0;
                 self.registers.f.subtract = false;
+                self.registers.f.half_carry = false;
+                self.write_target(target, new_value);
             },
             Instruction::RR(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.a & 0x1 != 0;
-                        self.registers.a = (self.registers.a >> 1) | (oldcarry << 7);
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::B => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.b & 0x1 != 0;
-                        self.registers.b = (self.registers.b >> 1) | (oldcarry << 7);
-                        self.registers.f.zero = self.registers.b == 0;
-                    }
-                    ArithmeticTarget::C => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.c & 0x1 != 0;
-                        self.registers.c = (self.registers.c >> 1) | (oldcarry << 7);
-                        self.registers.f.zero = self.registers.c == 0;
-                    }
-                    ArithmeticTarget::D => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.d & 0x1 != 0;
-                        self.registers.d = (self.registers.d >> 1) | (oldcarry << 7);
-                        self.registers.f.zero = self.registers.d == 0;
-                    }
-                    ArithmeticTarget::E => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.e & 0x1 != 0;
-                        self.registers.e = (self.registers.e >> 1) | (oldcarry << 7);
-                        self.registers.f.zero = self.registers.e == 0;
-                    }
-                    ArithmeticTarget::H => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.h & 0x1 != 0;
-                        self.registers.h = (self.registers.h >> 1) | (oldcarry << 7);
-                        self.registers.f.zero = self.registers.h == 0;
-                    }
-                    ArithmeticTarget::L => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.l & 0x1 != 0;
-                        self.registers.l = (self.registers.l >> 1) | (oldcarry << 7);
-                        self.registers.f.zero = self.registers.l == 0;
-                    }
-                }
+                let value = self.read_target(target);
+                let oldcarry = u8::from(self.registers.f.carry);
+                let new_value = (value >> 1) | (oldcarry << 7);
+                self.registers.f.carry = value & 1 != 0;
+                self.registers.f.zero = new_value == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
+                self.write_target(target, new_value);
             },
             Instruction::RL(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.a & 0x80 != 0;
-                        self.registers.a = (self.registers.a << 1) | oldcarry;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::B => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.b & 0x80 != 0;
-                        self.registers.b = (self.registers.b << 1) | oldcarry;
-                        self.registers.f.zero = self.registers.b == 0;
-                    }
-                    ArithmeticTarget::C => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.c & 0x80 != 0;
-                        self.registers.c = (self.registers.c << 1) | oldcarry;
-                        self.registers.f.zero = self.registers.c == 0;
-                    }
-                    ArithmeticTarget::D => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.d & 0x80 != 0;
-                        self.registers.d = (self.registers.d << 1) | oldcarry;
-                        self.registers.f.zero = self.registers.d == 0;
-                    }
-                    ArithmeticTarget::E => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.e & 0x80 != 0;
-                        self.registers.e = (self.registers.e << 1) | oldcarry;
-                        self.registers.f.zero = self.registers.e == 0;
-                    }
-                    ArithmeticTarget::H => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.h & 0x80 != 0;
-                        self.registers.h = (self.registers.h << 1) | oldcarry;
-                        self.registers.f.zero = self.registers.h == 0;
-                    }
-                    ArithmeticTarget::L => {
-                        let oldcarry = u8::from(self.registers.f.carry);
-                        self.registers.f.carry = self.registers.l & 0x80 != 0;
-                        self.registers.l = (self.registers.l << 1) | oldcarry;
-                        self.registers.f.zero = self.registers.l == 0;
-                    }
-                }
+                let value = self.read_target(target);
+                let oldcarry = u8::from(self.registers.f.carry);
+                let new_value = (value << 1) | oldcarry;
+                self.registers.f.carry = value & 0x80 != 0;
+                self.registers.f.zero = new_value == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
+                self.write_target(target, new_value);
             },
             Instruction::RRC(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.f.carry = self.registers.a & 1 != 0;
-                        self.registers.a = (self.registers.a >> 1) | (self.registers.a << 7);
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.f.carry = self.registers.b & 1 != 0;
-                        self.registers.b = (self.registers.b >> 1) | (self.registers.b << 7);
-                        self.registers.f.zero = self.registers.b == 0;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.f.carry = self.registers.c & 1 != 0;
-                        self.registers.c = (self.registers.c >> 1) | (self.registers.c << 7);
-                        self.registers.f.zero = self.registers.c == 0;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.f.carry = self.registers.d & 1 != 0;
-                        self.registers.d = (self.registers.d >> 1) | (self.registers.a << 7);
-                        self.registers.f.zero = self.registers.d == 0;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.f.carry = self.registers.e & 1 != 0;
-                        self.registers.e = (self.registers.e >> 1) | (self.registers.e << 7);
-                        self.registers.f.zero = self.registers.e == 0;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.f.carry = self.registers.h & 1 != 0;
-                        self.registers.h = (self.registers.h >> 1) | (self.registers.h << 7);
-                        self.registers.f.zero = self.registers.h == 0;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.f.carry = self.registers.l & 1 != 0;
-                        self.registers.l = (self.registers.l >> 1) | (self.registers.l << 7);
-                        self.registers.f.zero = self.registers.l == 0;
-                    }
-                }
+                let value = self.read_target(target);
+                let new_value = value.rotate_right(1);
+                self.registers.f.carry = value & 1 != 0;
+                self.registers.f.zero = new_value == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
+                self.write_target(target, new_value);
             },
             Instruction::RLC(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.f.carry = self.registers.a & 0x80 != 0;
-                        self.registers.a = (self.registers.a << 1) | (self.registers.a >> 7);
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.f.carry = self.registers.b & 0x80 != 0;
-                        self.registers.b = (self.registers.b << 1) | (self.registers.b >> 7);
-                        self.registers.f.zero = self.registers.b == 0;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.f.carry = self.registers.c & 0x80 != 0;
-                        self.registers.c = (self.registers.c << 1) | (self.registers.c >> 7);
-                        self.registers.f.zero = self.registers.c == 0;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.f.carry = self.registers.d & 0x80 != 0;
-                        self.registers.d = (self.registers.d << 1) | (self.registers.d >> 7);
-                        self.registers.f.zero = self.registers.d == 0;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.f.carry = self.registers.e & 0x80 != 0;
-                        self.registers.e = (self.registers.e << 1) | (self.registers.e >> 7);
-                        self.registers.f.zero = self.registers.e == 0;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.f.carry = self.registers.h & 0x80 != 0;
-                        self.registers.h = (self.registers.h << 1) | (self.registers.l >> 7);
-                        self.registers.f.zero = self.registers.h == 0;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.f.carry = self.registers.l & 0x80 != 0;
-                        self.registers.l = (self.registers.l << 1) | (self.registers.l >> 7);
-                        self.registers.f.zero = self.registers.l == 0;
-                    }
-                }
+                let value = self.read_target(target);
+                let new_value = value.rotate_left(1);
+                self.registers.f.carry = value & 0x80 != 0;
+                self.registers.f.zero = new_value == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
+                self.write_target(target, new_value);
             },
-            Instruction::SRA(target)  => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.f.carry = self.registers.a & 1 != 0;
-                        self.registers.a = (self.registers.a >> 1) | (self.registers.a & 0x80);
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.f.carry = self.registers.b & 1 != 0;
-                        self.registers.b = (self.registers.b >> 1) | (self.registers.b & 0x80);
-                        self.registers.f.zero = self.registers.b == 0;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.f.carry = self.registers.c & 1 != 0;
-                        self.registers.c = (self.registers.c >> 1) | (self.registers.c & 0x80);
-                        self.registers.f.zero = self.registers.c == 0;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.f.carry = self.registers.d & 1 != 0;
-                        self.registers.d = (self.registers.d >> 1) | (self.registers.a & 0x80);
-                        self.registers.f.zero = self.registers.d == 0;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.f.carry = self.registers.e & 1 != 0;
-                        self.registers.e = (self.registers.e >> 1) | (self.registers.e & 0x80);
-                        self.registers.f.zero = self.registers.e == 0;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.f.carry = self.registers.h & 1 != 0;
-                        self.registers.h = (self.registers.h >> 1) | (self.registers.h & 0x80);
-                        self.registers.f.zero = self.registers.h == 0;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.f.carry = self.registers.l & 1 != 0;
-                        self.registers.l = (self.registers.l >> 1) | (self.registers.l & 0x80);
-                        self.registers.f.zero = self.registers.l == 0;
-                    }
-                }
+            Instruction::SRA(target) => {
+                let value = self.read_target(target);
+                let new_value = (value >> 1) | (value & 0x80);
+                self.registers.f.carry = value & 1 != 0;
+                self.registers.f.zero = new_value == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
+                self.write_target(target, new_value);
             },
             Instruction::SLA(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.f.carry = self.registers.a & 0x80 != 0;
-                        self.registers.a = self.registers.a << 1;
-                        self.registers.f.zero = self.registers.a == 0;
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.f.carry = self.registers.b & 0x80 != 0;
-                        self.registers.b = self.registers.b << 1;
-                        self.registers.f.zero = self.registers.b == 0;
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.f.carry = self.registers.c & 0x80 != 0;
-                        self.registers.c = self.registers.c << 1;
-                        self.registers.f.zero = self.registers.c == 0;
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.f.carry = self.registers.d & 0x80 != 0;
-                        self.registers.d = self.registers.d << 1;
-                        self.registers.f.zero = self.registers.d == 0;
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.f.carry = self.registers.e & 0x80 != 0;
-                        self.registers.e = self.registers.e << 1;
-                        self.registers.f.zero = self.registers.e == 0;
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.f.carry = self.registers.h & 0x80 != 0;
-                        self.registers.h = self.registers.h << 1;
-                        self.registers.f.zero = self.registers.h == 0;
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.f.carry = self.registers.l & 0x80 != 0;
-                        self.registers.l = self.registers.l << 1;
-                        self.registers.f.zero = self.registers.l == 0;
-                    }
-                }
+                let value = self.read_target(target);
+                let new_value = value << 1;
+                self.registers.f.carry = value & 0x80 != 0;
+                self.registers.f.zero = new_value == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
+                self.write_target(target, new_value);
             },
             Instruction::SWAP(target) => {
-                match target {
-                    ArithmeticTarget::A => {
-                        self.registers.f.zero = self.registers.a == 0;
-                        self.registers.a = (self.registers.a << 4) | (self.registers.a << 4);
-                    }
-                    ArithmeticTarget::B => {
-                        self.registers.f.zero = self.registers.b == 0;
-                        self.registers.b = (self.registers.b << 4) | (self.registers.b << 4);
-                    }
-                    ArithmeticTarget::C => {
-                        self.registers.f.zero = self.registers.c == 0;
-                        self.registers.c = (self.registers.c << 4) | (self.registers.c << 4);
-                    }
-                    ArithmeticTarget::D => {
-                        self.registers.f.zero = self.registers.d == 0;
-                        self.registers.d = (self.registers.d << 4) | (self.registers.d << 4);
-                    }
-                    ArithmeticTarget::E => {
-                        self.registers.f.zero = self.registers.e == 0;
-                        self.registers.e = (self.registers.e << 4) | (self.registers.e << 4);
-                    }
-                    ArithmeticTarget::H => {
-                        self.registers.f.zero = self.registers.h == 0;
-                        self.registers.h = (self.registers.h << 4) | (self.registers.h << 4);
-                    }
-                    ArithmeticTarget::L => {
-                        self.registers.f.zero = self.registers.l == 0;
-                        self.registers.l = (self.registers.l << 4) | (self.registers.l << 4);
-                    }
-                }
+                let value = self.read_target(target);
+                let new_value = value.rotate_left(4);
                 self.registers.f.carry = false;
+                self.registers.f.zero = new_value == 0;
                 self.registers.f.subtract = false;
                 self.registers.f.half_carry = false;
+                self.write_target(target, new_value);
+            },
+            Instruction::LD(load_type) => {
+                match load_type {
+                    LoadType::Byte(target, source) => {
+                        let value = match source {
+                            LoadByteSource::A => self.registers.a,
+                            LoadByteSource::B => self.registers.b,
+                            LoadByteSource::C => self.registers.c,
+                            LoadByteSource::D => self.registers.d,
+                            LoadByteSource::E => self.registers.e,
+                            LoadByteSource::H => self.registers.h,
+                            LoadByteSource::L => self.registers.l,
+                            LoadByteSource::D8 => self.read_next_byte(),
+                            LoadByteSource::HLI => self.read_byte(self.registers.get_hl()),
+                        };
+                        match target {
+                            LoadByteTarget::A => self.registers.a = value,
+                            LoadByteTarget::B => self.registers.b = value,
+                            LoadByteTarget::C => self.registers.c = value,
+                            LoadByteTarget::D => self.registers.d = value,
+                            LoadByteTarget::E => self.registers.e = value,
+                            LoadByteTarget::H => self.registers.h = value,
+                            LoadByteTarget::L => self.registers.l = value,
+                            LoadByteTarget::HLI => self.write_byte(self.registers.get_hl(), value),
+                        }
+                    }
+                    LoadType::Word(target) => {
+                        let value = self.read_next_word();
+                        match target {
+                            LoadWordTarget::BC => self.registers.set_bc(value),
+                            LoadWordTarget::DE => self.registers.set_de(value),
+                            LoadWordTarget::HL => self.registers.set_hl(value),
+                            LoadWordTarget::SP => self.sp = value,
+                        }
+                    }
+                    LoadType::AFromIndirect(source) => {
+                        let address = self.indirect_address(source);
+                        self.registers.a = self.read_byte(address);
+                    }
+                    LoadType::IndirectFromA(target) => {
+                        let address = self.indirect_address(target);
+                        self.write_byte(address, self.registers.a);
+                    }
+                    LoadType::AFromByteAddress => {
+                        let address = 0xFF00 | self.read_next_byte() as u16;
+                        self.registers.a = self.read_byte(address);
+                    }
+                    LoadType::ByteAddressFromA => {
+                        let address = 0xFF00 | self.read_next_byte() as u16;
+                        self.write_byte(address, self.registers.a);
+                    }
+                    LoadType::SPFromHL => {
+                        self.sp = self.registers.get_hl();
+                        self.tick();
+                    }
+                    LoadType::IndirectFromSP => {
+                        let address = self.read_next_word();
+                        self.write_byte(address, self.sp as u8);
+                        self.write_byte(address.wrapping_add(1), (self.sp >> 8) as u8);
+                    }
+                    LoadType::HLFromSPN => {
+                        let offset = self.read_next_byte();
+                        let value = self.add_sp_signed(offset);
+                        self.registers.set_hl(value);
+                        self.tick();
+                    }
+                }
+            },
+            Instruction::JP(test) => {
+                let jump_condition = self.jump_condition(test);
+                let address = self.read_next_word();
+                if jump_condition {
+                    self.tick();
+                    self.pc = address;
+                }
+            },
+            Instruction::JPHL() => {
+                self.pc = self.registers.get_hl();
+            },
+            Instruction::JR(test) => {
+                let jump_condition = self.jump_condition(test);
+                let offset = self.read_next_byte() as i8;
+                if jump_condition {
+                    self.tick();
+                    self.pc = self.pc.wrapping_add(offset as i16 as u16);
+                }
+            },
+            Instruction::CALL(test) => {
+                let jump_condition = self.jump_condition(test);
+                let address = self.read_next_word();
+                if jump_condition {
+                    self.tick();
+                    self.push(self.pc);
+                    self.pc = address;
+                }
+            },
+            Instruction::RET(test) => {
+                // A conditional RET spends an extra cycle evaluating its condition
+                if !matches!(test, JumpTest::Always) {
+                    self.tick();
+                }
+                if self.jump_condition(test) {
+                    self.pc = self.pop();
+                    self.tick();
+                }
+            },
+            Instruction::RETI() => {
+                self.pc = self.pop();
+                self.tick();
+                self.ime = true;
+            },
+            Instruction::RST(vector) => {
+                self.tick();
+                self.push(self.pc);
+                self.pc = vector as u16;
+            },
+            Instruction::DAA() => {
+                let mut adjust = 0;
+                let mut carry = self.registers.f.carry;
+                if self.registers.f.subtract {
+                    if self.registers.f.carry {
+                        adjust |= 0x60;
+                    }
+                    if self.registers.f.half_carry {
+                        adjust |= 0x06;
+                    }
+                    self.registers.a = self.registers.a.wrapping_sub(adjust);
+                } else {
+                    if self.registers.f.carry || self.registers.a > 0x99 {
+                        adjust |= 0x60;
+                        carry = true;
+                    }
+                    if self.registers.f.half_carry || self.registers.a & 0xF > 0x9 {
+                        adjust |= 0x06;
+                    }
+                    self.registers.a = self.registers.a.wrapping_add(adjust);
+                }
+                self.registers.f.zero = self.registers.a == 0;
+                self.registers.f.half_carry = false;
+                self.registers.f.carry = carry;
             },
+            Instruction::NOP() => {
+            },
+            Instruction::HALT() => {
+                if !self.ime && self.bus.pending_interrupts() != 0 {
+                    self.halt_bug = true;
+                } else {
+                    self.state = CPUState::Halted;
+                }
+            },
+            Instruction::STOP() => {
+                // STOP is two bytes long, the second one is ignored. It also resets DIV
+                self.read_next_byte();
+                self.bus.write_byte(0xFF04, 0);
+                self.state = CPUState::Stopped;
+            },
+            Instruction::DI() => {
+                self.ime = false;
+                self.ime_scheduled = false;
+            },
+            Instruction::EI() => {
+                self.ime_scheduled = true;
+            },
+            Instruction::PUSH(target) => {
+                let value = match target {
+                    StackTarget::BC => self.registers.get_bc(),
+                    StackTarget::DE => self.registers.get_de(),
+                    StackTarget::HL => self.registers.get_hl(),
+                    StackTarget::AF => self.registers.get_af(),
+                };
+                self.tick();
+                self.push(value);
+            },
+            Instruction::POP(target) => {
+                let value = self.pop();
+                match target {
+                    StackTarget::BC => self.registers.set_bc(value),
+                    StackTarget::DE => self.registers.set_de(value),
+                    StackTarget::HL => self.registers.set_hl(value),
+                    // The low nibble of F does not exist in hardware and always reads back as zero
+                    StackTarget::AF => self.registers.set_af(value & 0xFFF0),
+                }
+            },
+        }
+    }
+
+    fn jump_condition(&self, test: JumpTest) -> bool {
+        match test {
+            JumpTest::NotZero => !self.registers.f.zero,
+            JumpTest::Zero => self.registers.f.zero,
+            JumpTest::NotCarry => !self.registers.f.carry,
+            JumpTest::Carry => self.registers.f.carry,
+            JumpTest::Always => true,
+        }
+    }
+
+    fn push(&mut self, value: u16) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_byte(self.sp, ((value & 0xFF00) >> 8) as u8);
+
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_byte(self.sp, (value & 0xFF) as u8);
+    }
+
+    fn pop(&mut self) -> u16 {
+        let low = self.read_byte(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+
+        let high = self.read_byte(self.sp) as u16;
+        self.sp = self.sp.wrapping_add(1);
+
+        (high << 8) | low
+    }
+
+    // Resolves an indirect operand to its address, applying the HL+/HL- side effects
+    fn indirect_address(&mut self, indirect: Indirect) -> u16 {
+        match indirect {
+            Indirect::BC => self.registers.get_bc(),
+            Indirect::DE => self.registers.get_de(),
+            Indirect::HLPlus => {
+                let hl = self.registers.get_hl();
+                self.registers.set_hl(hl.wrapping_add(1));
+                hl
+            }
+            Indirect::HLMinus => {
+                let hl = self.registers.get_hl();
+                self.registers.set_hl(hl.wrapping_sub(1));
+                hl
+            }
+            Indirect::Word => self.read_next_word(),
+            Indirect::LastByte => 0xFF00 | self.registers.c as u16,
         }
     }
 
-    fn add(&mut self, value: u8) -> u8 {
-        let (new_value, did_overflow) = self.registers.a.overflowing_add(value);
-        self.registers.f.zero = (new_value == 0);
+    // SP plus a signed 8-bit offset. Flags come from the unsigned add of the offset to the low byte of SP
+    fn add_sp_signed(&mut self, offset: u8) -> u16 {
+        let value = self.sp.wrapping_add(offset as i8 as i16 as u16);
+        self.registers.f.zero = false;
         self.registers.f.subtract = false;
-        self.registers.f.carry = did_overflow;
-        self.registers.f.half_carry = (self.registers.a & 0xF) + (value & 0xF) > 0xF;
+        self.registers.f.half_carry = (self.sp & 0xF) + (offset as u16 & 0xF) > 0xF;
+        self.registers.f.carry = (self.sp & 0xFF) + offset as u16 > 0xFF;
+        value
+    }
+
+    fn read_target(&mut self, target: ArithmeticTarget) -> u8 {
+        match target {
+            ArithmeticTarget::A => self.registers.a,
+            ArithmeticTarget::B => self.registers.b,
+            ArithmeticTarget::C => self.registers.c,
+            ArithmeticTarget::D => self.registers.d,
+            ArithmeticTarget::E => self.registers.e,
+            ArithmeticTarget::H => self.registers.h,
+            ArithmeticTarget::L => self.registers.l,
+            ArithmeticTarget::HLI => self.read_byte(self.registers.get_hl()),
+            ArithmeticTarget::D8 => self.read_next_byte(),
+        }
+    }
+
+    fn read_word_target(&self, target: WordTarget) -> u16 {
+        match target {
+            WordTarget::BC => self.registers.get_bc(),
+            WordTarget::DE => self.registers.get_de(),
+            WordTarget::HL => self.registers.get_hl(),
+            WordTarget::SP => self.sp,
+        }
+    }
+
+    fn write_word_target(&mut self, target: WordTarget, value: u16) {
+        match target {
+            WordTarget::BC => self.registers.set_bc(value),
+            WordTarget::DE => self.registers.set_de(value),
+            WordTarget::HL => self.registers.set_hl(value),
+            WordTarget::SP => self.sp = value,
+        }
+    }
+
+    fn write_target(&mut self, target: ArithmeticTarget, value: u8) {
+        match target {
+            ArithmeticTarget::A => self.registers.a = value,
+            ArithmeticTarget::B => self.registers.b = value,
+            ArithmeticTarget::C => self.registers.c = value,
+            ArithmeticTarget::D => self.registers.d = value,
+            ArithmeticTarget::E => self.registers.e = value,
+            ArithmeticTarget::H => self.registers.h = value,
+            ArithmeticTarget::L => self.registers.l = value,
+            ArithmeticTarget::HLI => self.write_byte(self.registers.get_hl(), value),
+            ArithmeticTarget::D8 => unreachable!("immediate operands are read-only"),
+        }
+    }
+
+    fn add(&mut self, value: u8, carry: bool) -> u8 {
+        let carry = u8::from(carry);
+        let new_value = self.registers.a.wrapping_add(value).wrapping_add(carry);
+        self.registers.f.zero = new_value == 0;
+        self.registers.f.subtract = false;
+        self.registers.f.carry = (self.registers.a as u16) + (value as u16) + (carry as u16) > 0xFF;
+        self.registers.f.half_carry = (self.registers.a & 0xF) + (value & 0xF) + carry > 0xF;
         new_value
     }
-    fn addhl(&mut self, value: u8) -> u16 {
-        let (new_value, did_overflow) = self.registers.get_hl().overflowing_add(value as u16);
-        self.registers.f.zero = (new_value == 0);
+    // Zero is left untouched; half carry comes out of bit 11 and carry out of bit 15
+    fn addhl(&mut self, value: u16) -> u16 {
+        let hl = self.registers.get_hl();
+        let (new_value, did_overflow) = hl.overflowing_add(value);
         self.registers.f.subtract = false;
         self.registers.f.carry = did_overflow;
-        self.registers.f.half_carry = (self.registers.a & 0xF) + (value & 0xF) > 0xF;
+        self.registers.f.half_carry = (hl & 0xFFF) + (value & 0xFFF) > 0xFFF;
         new_value
     }
-    fn sub(&mut self, value: u8) -> u8 {
-        let (new_value, did_overflow) = self.registers.a.overflowing_sub(value);
-        self.registers.f.zero = (new_value == 0);
+    fn sub(&mut self, value: u8, carry: bool) -> u8 {
+        let carry = u8::from(carry);
+        let new_value = self.registers.a.wrapping_sub(value).wrapping_sub(carry);
+        self.registers.f.zero = new_value == 0;
         self.registers.f.subtract = true;
-        self.registers.f.carry = did_overflow;
-        self.registers.f.half_carry = (self.registers.a & 0xF) + (value & 0xF) < 0xF;
+        self.registers.f.carry = (self.registers.a as u16) < (value as u16) + (carry as u16);
+        self.registers.f.half_carry = (self.registers.a & 0xF) < (value & 0xF) + carry;
         new_value
     }
 }
 
-//Instructions
\ No newline at end of file
+//Instructions
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const Z: u8 = 0x80;
+    const N: u8 = 0x40;
+    const H: u8 = 0x20;
+    const C: u8 = 0x10;
+
+    const ILLEGAL_OPCODES: [u8; 11] = [0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD];
+
+    fn new_cpu() -> CPU {
+        CPU::new(MemoryBus::new(Renderer::Scanline))
+    }
+
+    // Copies a program to 0xC000 and points PC at it
+    fn load_program(cpu: &mut CPU, program: &[u8]) {
+        for (i, &byte) in program.iter().enumerate() {
+            cpu.bus.write_byte(0xC000 + i as u16, byte);
+        }
+        cpu.pc = 0xC000;
+        cpu.sp = 0xFFFE;
+    }
+
+    // Runs one instruction with A and F set up, returns A and F afterwards
+    fn run(instruction: Instruction, a: u8, f: u8) -> (u8, u8) {
+        let mut cpu = new_cpu();
+        cpu.registers.a = a;
+        cpu.registers.f = FlagsRegister::from(f);
+        cpu.execute(instruction);
+        (cpu.registers.a, u8::from(cpu.registers.f))
+    }
+
+    #[test]
+    fn decodes_each_opcode_group() {
+        let decode = |byte, prefixed| Instruction::from_byte(byte, prefixed).unwrap();
+        assert!(matches!(decode(0x00, false), Instruction::NOP()));
+        assert!(matches!(decode(0x07, false), Instruction::RLCA()));
+        assert!(matches!(decode(0x0F, false), Instruction::RRCA()));
+        assert!(matches!(decode(0x17, false), Instruction::RLA()));
+        assert!(matches!(decode(0x1F, false), Instruction::RRA()));
+        assert!(matches!(decode(0x27, false), Instruction::DAA()));
+        assert!(matches!(decode(0x2F, false), Instruction::CPL()));
+        assert!(matches!(decode(0x37, false), Instruction::SCF()));
+        assert!(matches!(decode(0x3F, false), Instruction::CCF()));
+        assert!(matches!(decode(0x09, false), Instruction::ADDHL(WordTarget::BC)));
+        assert!(matches!(decode(0x33, false), Instruction::INC16(WordTarget::SP)));
+        assert!(matches!(decode(0x2B, false), Instruction::DEC16(WordTarget::HL)));
+        assert!(matches!(decode(0x34, false), Instruction::INC(ArithmeticTarget::HLI)));
+        assert!(matches!(decode(0x3D, false), Instruction::DEC(ArithmeticTarget::A)));
+        assert!(matches!(decode(0x41, false), Instruction::LD(LoadType::Byte(LoadByteTarget::B, LoadByteSource::C))));
+        assert!(matches!(decode(0x76, false), Instruction::HALT()));
+        assert!(matches!(decode(0x80, false), Instruction::ADD(ArithmeticTarget::B)));
+        assert!(matches!(decode(0x8E, false), Instruction::ADC(ArithmeticTarget::HLI)));
+        assert!(matches!(decode(0x97, false), Instruction::SUB(ArithmeticTarget::A)));
+        assert!(matches!(decode(0x9A, false), Instruction::SBC(ArithmeticTarget::D)));
+        assert!(matches!(decode(0xA3, false), Instruction::AND(ArithmeticTarget::E)));
+        assert!(matches!(decode(0xAC, false), Instruction::XOR(ArithmeticTarget::H)));
+        assert!(matches!(decode(0xB5, false), Instruction::OR(ArithmeticTarget::L)));
+        assert!(matches!(decode(0xFE, false), Instruction::CP(ArithmeticTarget::D8)));
+        assert!(matches!(decode(0xC2, false), Instruction::JP(JumpTest::NotZero)));
+        assert!(matches!(decode(0xE9, false), Instruction::JPHL()));
+        assert!(matches!(decode(0x38, false), Instruction::JR(JumpTest::Carry)));
+        assert!(matches!(decode(0xCD, false), Instruction::CALL(JumpTest::Always)));
+        assert!(matches!(decode(0xC8, false), Instruction::RET(JumpTest::Zero)));
+        assert!(matches!(decode(0xD9, false), Instruction::RETI()));
+        assert!(matches!(decode(0xEF, false), Instruction::RST(0x28)));
+        assert!(matches!(decode(0xF5, false), Instruction::PUSH(StackTarget::AF)));
+        assert!(matches!(decode(0xC1, false), Instruction::POP(StackTarget::BC)));
+        assert!(matches!(decode(0xE8, false), Instruction::ADDSP()));
+        assert!(matches!(decode(0xF3, false), Instruction::DI()));
+        assert!(matches!(decode(0xFB, false), Instruction::EI()));
+
+        assert!(matches!(decode(0x00, true), Instruction::RLC(ArithmeticTarget::B)));
+        assert!(matches!(decode(0x0E, true), Instruction::RRC(ArithmeticTarget::HLI)));
+        assert!(matches!(decode(0x11, true), Instruction::RL(ArithmeticTarget::C)));
+        assert!(matches!(decode(0x1F, true), Instruction::RR(ArithmeticTarget::A)));
+        assert!(matches!(decode(0x22, true), Instruction::SLA(ArithmeticTarget::D)));
+        assert!(matches!(decode(0x2B, true), Instruction::SRA(ArithmeticTarget::E)));
+        assert!(matches!(decode(0x34, true), Instruction::SWAP(ArithmeticTarget::H)));
+        assert!(matches!(decode(0x3D, true), Instruction::SRL(ArithmeticTarget::L)));
+        assert!(matches!(decode(0x7E, true), Instruction::BIT(ArithmeticTarget::HLI, 7)));
+        assert!(matches!(decode(0x87, true), Instruction::RES(ArithmeticTarget::A, 0)));
+        assert!(matches!(decode(0xD8, true), Instruction::SET(ArithmeticTarget::B, 3)));
+    }
+
+    #[test]
+    fn sets_flags() {
+        // Rotates of A always clear Z
+        assert_eq!(run(Instruction::RLCA(), 0x80, Z), (0x01, C));
+        assert_eq!(run(Instruction::RLCA(), 0x01, C), (0x02, 0));
+        assert_eq!(run(Instruction::RRCA(), 0x01, 0), (0x80, C));
+        assert_eq!(run(Instruction::RLA(), 0x80, 0), (0x00, C));
+        assert_eq!(run(Instruction::RRA(), 0x00, C), (0x80, 0));
+
+        assert_eq!(run(Instruction::ADD(ArithmeticTarget::A), 0x08, 0), (0x10, H));
+        assert_eq!(run(Instruction::ADD(ArithmeticTarget::A), 0x80, 0), (0x00, Z | C));
+        assert_eq!(run(Instruction::ADC(ArithmeticTarget::A), 0x0F, C), (0x1F, H));
+        assert_eq!(run(Instruction::SUB(ArithmeticTarget::A), 0x42, 0), (0x00, Z | N));
+        assert_eq!(run(Instruction::SBC(ArithmeticTarget::A), 0x42, C), (0xFF, N | H | C));
+        assert_eq!(run(Instruction::CP(ArithmeticTarget::A), 0x42, 0), (0x42, Z | N));
+        assert_eq!(run(Instruction::AND(ArithmeticTarget::A), 0x00, C), (0x00, Z | H));
+        assert_eq!(run(Instruction::XOR(ArithmeticTarget::A), 0x5A, C), (0x00, Z));
+        assert_eq!(run(Instruction::OR(ArithmeticTarget::A), 0x5A, Z), (0x5A, 0));
+
+        // INC and DEC leave carry alone
+        assert_eq!(run(Instruction::INC(ArithmeticTarget::A), 0xFF, C), (0x00, Z | H | C));
+        assert_eq!(run(Instruction::DEC(ArithmeticTarget::A), 0x10, 0), (0x0F, N | H));
+        assert_eq!(run(Instruction::DEC(ArithmeticTarget::A), 0x01, C), (0x00, Z | N | C));
+
+        assert_eq!(run(Instruction::DAA(), 0x0F, 0), (0x15, 0));
+        assert_eq!(run(Instruction::DAA(), 0x9A, 0), (0x00, Z | C));
+        assert_eq!(run(Instruction::DAA(), 0x0F, N | H), (0x09, N));
+        assert_eq!(run(Instruction::CPL(), 0x0F, 0), (0xF0, N | H));
+        assert_eq!(run(Instruction::SCF(), 0x00, Z | N | H), (0x00, Z | C));
+        assert_eq!(run(Instruction::CCF(), 0x00, C), (0x00, 0));
+
+        assert_eq!(run(Instruction::RLC(ArithmeticTarget::A), 0x80, 0), (0x01, C));
+        assert_eq!(run(Instruction::RRC(ArithmeticTarget::A), 0x01, 0), (0x80, C));
+        assert_eq!(run(Instruction::RL(ArithmeticTarget::A), 0x80, 0), (0x00, Z | C));
+        assert_eq!(run(Instruction::RR(ArithmeticTarget::A), 0x01, C), (0x80, C));
+        assert_eq!(run(Instruction::SLA(ArithmeticTarget::A), 0xC0, 0), (0x80, C));
+        assert_eq!(run(Instruction::SRA(ArithmeticTarget::A), 0x81, 0), (0xC0, C));
+        assert_eq!(run(Instruction::SRL(ArithmeticTarget::A), 0x01, 0), (0x00, Z | C));
+        assert_eq!(run(Instruction::SWAP(ArithmeticTarget::A), 0xF0, C), (0x0F, 0));
+        assert_eq!(run(Instruction::BIT(ArithmeticTarget::A, 7), 0x7F, C), (0x7F, Z | H | C));
+        assert_eq!(run(Instruction::SET(ArithmeticTarget::A, 0), 0x00, 0), (0x01, 0));
+        assert_eq!(run(Instruction::RES(ArithmeticTarget::A, 7), 0xFF, 0), (0x7F, 0));
+    }
+
+    #[test]
+    fn sets_sixteen_bit_flags() {
+        let mut cpu = new_cpu();
+        cpu.registers.f = FlagsRegister::from(Z);
+        cpu.registers.set_hl(0x0FFF);
+        cpu.registers.set_bc(0x0001);
+        cpu.execute(Instruction::ADDHL(WordTarget::BC));
+        assert_eq!(cpu.registers.get_hl(), 0x1000);
+        assert_eq!(u8::from(cpu.registers.f), Z | H);
+
+        // ADD SP,e takes its flags from the low byte
+        let mut cpu = new_cpu();
+        cpu.sp = 0xFFF8;
+        cpu.bus.write_byte(0xC000, 0x08);
+        cpu.pc = 0xC000;
+        cpu.execute(Instruction::ADDSP());
+        assert_eq!(cpu.sp, 0x0000);
+        assert_eq!(u8::from(cpu.registers.f), H | C);
+    }
+
+    #[test]
+    fn rejects_illegal_opcodes() {
+        for byte in 0..=0xFFu8 {
+            let result = Instruction::from_byte(byte, false);
+            if ILLEGAL_OPCODES.contains(&byte) {
+                assert!(matches!(result, Err(DecodeError::IllegalOpcode(opcode)) if opcode == byte));
+            } else if byte == 0xCB {
+                assert!(matches!(result, Err(DecodeError::PrefixWithoutOpcode)));
+            } else {
+                assert!(result.is_ok(), "{:#04x} should decode", byte);
+            }
+            assert!(Instruction::from_byte(byte, true).is_ok());
+        }
+    }
+
+    #[test]
+    fn ei_before_halt_returns_to_the_halt() {
+        // EI; HALT with the timer interrupt already pending. Without a cartridge the handler
+        // reads as 0xFF, RST 38
+        let mut cpu = new_cpu();
+        load_program(&mut cpu, &[0xFB, 0x76, 0x00]);
+        cpu.bus.interrupt_enable = 0x04;
+        cpu.bus.interrupt_flag = 0x04;
+
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0050);
+        assert!(!cpu.halt_bug);
+        assert_eq!(cpu.bus.read_byte(0xFFFC), 0x01);
+        assert_eq!(cpu.bus.read_byte(0xFFFD), 0xC0);
+
+        // The handler runs its first opcode once
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0x0038);
+        assert_eq!(cpu.bus.read_byte(0xFFFA), 0x51);
+        assert_eq!(cpu.bus.read_byte(0xFFFB), 0x00);
+    }
+
+    #[test]
+    fn interrupts_wait_for_ime() {
+        let mut cpu = new_cpu();
+        load_program(&mut cpu, &[0x00]);
+        cpu.bus.interrupt_enable = 0x01;
+        cpu.bus.interrupt_flag = 0x01;
+        cpu.step().unwrap();
+        assert_eq!(cpu.pc, 0xC001);
+        assert_eq!(cpu.bus.interrupt_flag & 0x1F, 0x01);
+    }
+
+    #[test]
+    fn dispatches_highest_priority_interrupt() {
+        let mut cpu = new_cpu();
+        load_program(&mut cpu, &[0x00]);
+        cpu.ime = true;
+        cpu.bus.interrupt_enable = 0x1F;
+        cpu.bus.interrupt_flag = 0x14;
+        assert_eq!(cpu.step().unwrap(), 5);
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(cpu.sp, 0xFFFC);
+        assert!(!cpu.ime);
+        // Only the dispatched request is acknowledged
+        assert_eq!(cpu.bus.interrupt_flag & 0x1F, 0x10);
+    }
+
+    #[test]
+    fn wakes_from_halt() {
+        // Without IME the CPU carries on after the HALT
+        let mut cpu = new_cpu();
+        load_program(&mut cpu, &[0x76, 0x00]);
+        cpu.bus.interrupt_enable = 0x04;
+        cpu.step().unwrap();
+        assert!(cpu.state == CPUState::Halted);
+        assert_eq!(cpu.step().unwrap(), 1);
+        assert_eq!(cpu.pc, 0xC001);
+        cpu.bus.interrupt_flag = 0x04;
+        cpu.step().unwrap();
+        assert!(cpu.state == CPUState::Running);
+        assert_eq!(cpu.pc, 0xC002);
+
+        // With IME the interrupt is dispatched and returns after the HALT
+        let mut cpu = new_cpu();
+        load_program(&mut cpu, &[0x76, 0x00]);
+        cpu.ime = true;
+        cpu.bus.interrupt_enable = 0x04;
+        cpu.step().unwrap();
+        cpu.bus.interrupt_flag = 0x04;
+        cpu.step().unwrap();
+        assert!(cpu.state == CPUState::Running);
+        assert_eq!(cpu.pc, 0x0050);
+        assert_eq!(cpu.bus.read_byte(0xFFFC), 0x01);
+        assert_eq!(cpu.bus.read_byte(0xFFFD), 0xC0);
+    }
+}
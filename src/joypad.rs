@@ -0,0 +1,94 @@
+// Writing 0 to one of these bits of P1 selects that group of buttons
+const SELECT_DIRECTIONS: u8 = 1 << 4;
+const SELECT_ACTIONS: u8 = 1 << 5;
+
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    // Directions sit on the low nibble, actions on the high one, both in P10-P13 order
+    fn mask(&self) -> u8 {
+        match self {
+            Button::Right => 1 << 0,
+            Button::Left => 1 << 1,
+            Button::Up => 1 << 2,
+            Button::Down => 1 << 3,
+            Button::A => 1 << 4,
+            Button::B => 1 << 5,
+            Button::Select => 1 << 6,
+            Button::Start => 1 << 7,
+        }
+    }
+}
+
+pub struct Joypad {
+    // Bits 4 and 5 of P1 as last written
+    select: u8,
+    // One bit per button, set while held
+    pressed: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+            pressed: 0,
+        }
+    }
+
+    // Buttons currently pulling P10-P13 low through the selected groups
+    fn lines(&self) -> u8 {
+        let mut lines = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            lines |= self.pressed & 0x0F;
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            lines |= self.pressed >> 4;
+        }
+        lines
+    }
+
+    // True if any of P10-P13 is low, which is also what wakes the CPU from STOP
+    pub fn any_line_low(&self) -> bool {
+        self.lines() != 0
+    }
+
+    // The joypad interrupt is requested when any line goes from high to low
+    fn line_fell(&self, old_lines: u8) -> bool {
+        self.lines() & !old_lines != 0
+    }
+
+    // Returns true when the joypad interrupt should be requested
+    pub fn press(&mut self, button: Button) -> bool {
+        let old_lines = self.lines();
+        self.pressed |= button.mask();
+        self.line_fell(old_lines)
+    }
+
+    pub fn release(&mut self, button: Button) -> bool {
+        let old_lines = self.lines();
+        self.pressed &= !button.mask();
+        self.line_fell(old_lines)
+    }
+
+    // The lines are active low and the top two bits are unused
+    pub fn read_byte(&self) -> u8 {
+        0xC0 | self.select | (!self.lines() & 0x0F)
+    }
+
+    // Selecting a group with a button already held also pulls a line low
+    pub fn write_byte(&mut self, value: u8) -> bool {
+        let old_lines = self.lines();
+        self.select = value & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+        self.line_fell(old_lines)
+    }
+}
@@ -1,34 +1,110 @@
-use minifb::{Scale, Window, WindowOptions};
-use crate::emulator::{ArithmeticTarget, CPU, FlagsRegister, Instruction, Registers};
+use std::time::{Duration, Instant};
+use minifb::{Key, Scale, Window, WindowOptions};
+use crate::cartridge::Cartridge;
+use crate::emulator::{CPU, MemoryBus, Model};
+use crate::joypad::Button;
+use crate::ppu::{Renderer, SCREEN_HEIGHT, SCREEN_WIDTH};
 
-const WIDTH: usize = 512;
-const HEIGHT: usize = 512;
+const WIDTH: usize = SCREEN_WIDTH;
+const HEIGHT: usize = SCREEN_HEIGHT;
 
+// The four DMG shades, from white to black
+const SHADES: [u32; 4] = [0x00E0F8D0, 0x0088C070, 0x00346856, 0x00081820];
+
+const KEYS: [(Key, Button); 8] = [
+    (Key::Right, Button::Right),
+    (Key::Left, Button::Left),
+    (Key::Up, Button::Up),
+    (Key::Down, Button::Down),
+    (Key::X, Button::A),
+    (Key::Z, Button::B),
+    (Key::Backspace, Button::Select),
+    (Key::Enter, Button::Start),
+];
+
+// Battery-backed RAM is flushed to disk about every five seconds of emulated time
+const CYCLES_PER_SAVE: u32 = 5 * 1_048_576;
+
+// One LCD frame is 154 lines of 114 machine cycles
+const CYCLES_PER_FRAME: u32 = 154 * 114;
+const FRAME_DURATION: Duration = Duration::from_nanos(CYCLES_PER_FRAME as u64 * 1_000_000_000 / 1_048_576);
+
+mod cartridge;
+mod dma;
 mod emulator;
+mod joypad;
+mod mbc;
+mod ppu;
+mod serial;
+mod timer;
 
 fn main() {
-    let mut cpu = CPU{
-        registers: Registers{
-            a: 0,
-            b: 0b10010001,
-            c: 0,
-            d: 0,
-            e: 0,
-            f: FlagsRegister{
-                zero: false,
-                subtract: false,
-                half_carry: false,
-                carry: false,
-            },
-            h: 0,
-            l: 0,
-        },
+    let mut rom_path = None;
+    let mut boot_rom_path = None;
+    let mut model = Model::DMG;
+    let mut renderer = Renderer::Scanline;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--boot" => boot_rom_path = args.next(),
+            "--cgb" => model = Model::CGB,
+            "--fifo" => renderer = Renderer::Fifo,
+            _ => rom_path = Some(arg),
+        }
+    }
+    let rom_path = match rom_path {
+        Some(path) => path,
+        None => {
+            println!("Usage: gb [--boot <boot.bin>] [--cgb] [--fifo] <rom.gb>");
+            return;
+        }
+    };
+    let mut cartridge = match Cartridge::from_file(&rom_path) {
+        Ok(cartridge) => cartridge,
+        Err(error) => {
+            println!("Could not load {}: {}", rom_path, error);
+            return;
+        }
     };
+    let header = &cartridge.header;
+    println!("{} v{} ({:?}, {} KiB ROM, {} KiB RAM, licensee {}, CGB {:?}, SGB {})",
+        header.title,
+        header.version,
+        header.cartridge_type.controller,
+        header.rom_size / 1024,
+        header.ram_size / 1024,
+        header.licensee,
+        header.cgb,
+        if header.sgb { "yes" } else { "no" });
+    if !header.global_checksum_valid {
+        println!("Warning: global checksum does not match the ROM contents");
+    }
+    cartridge.set_rumble_callback(Box::new(|active| {
+        println!("Rumble {}", if active { "on" } else { "off" });
+    }));
 
-    cpu.execute(Instruction::SRL(ArithmeticTarget::B));
-    cpu.print();
+    let mut cpu = CPU::new(MemoryBus::new(renderer));
+    cpu.bus.load_cartridge(cartridge);
 
-    /*let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
+    match boot_rom_path {
+        Some(path) => {
+            let boot_rom = match std::fs::read(&path) {
+                Ok(boot_rom) => boot_rom,
+                Err(error) => {
+                    println!("Could not load boot ROM {}: {}", path, error);
+                    return;
+                }
+            };
+            if Model::from_boot_rom(&boot_rom).is_none() {
+                println!("{} is {} bytes, not a DMG or CGB boot ROM", path, boot_rom.len());
+                return;
+            }
+            cpu.bus.load_boot_rom(boot_rom);
+        }
+        None => cpu.skip_boot_rom(model),
+    }
+
+    let mut buffer: Vec<u32> = vec![0; WIDTH * HEIGHT];
 
     let mut window = Window::new(
         "Emulator",
@@ -36,16 +112,61 @@ fn main() {
         HEIGHT,
         WindowOptions {
             resize: true,
+            scale: Scale::X4,
             ..WindowOptions::default()
         },
     ).unwrap();
     window.set_position(450, 120);
 
-    while window.is_open() {
-        /*buffer[((200 /*y*/ as usize) * (WIDTH)) + 200 /*x*/ as usize] = 0x00ffffff;*/
+    let mut cycles_since_save = 0;
+    'emulation: while window.is_open() {
+        let frame_start = Instant::now();
+
+        for &(key, button) in KEYS.iter() {
+            if window.is_key_down(key) {
+                cpu.bus.press(button);
+            } else {
+                cpu.bus.release(button);
+            }
+        }
+
+        // Run until the PPU finishes a frame, or for as long as one would take while the LCD is off
+        let mut frame_cycles = 0;
+        while !cpu.bus.ppu.frame_ready && frame_cycles < CYCLES_PER_FRAME {
+            match cpu.step() {
+                Ok(cycles) => frame_cycles += cycles as u32,
+                Err(error) => {
+                    println!("{} at {:#06x}", error, cpu.pc);
+                    cpu.print();
+                    break 'emulation;
+                }
+            }
+        }
+        cpu.bus.ppu.frame_ready = false;
 
+        for (pixel, &shade) in buffer.iter_mut().zip(cpu.bus.ppu.framebuffer.iter()) {
+            *pixel = SHADES[shade as usize];
+        }
         window.update_with_buffer(&buffer, WIDTH, HEIGHT).expect("Oops!");
-        buffer.clear();
-        buffer.resize(WIDTH*HEIGHT,0);
-    }*/
-}
\ No newline at end of file
+
+        cycles_since_save += frame_cycles;
+        if cycles_since_save >= CYCLES_PER_SAVE {
+            cycles_since_save = 0;
+            save(&mut cpu, false);
+        }
+
+        if let Some(remaining) = FRAME_DURATION.checked_sub(frame_start.elapsed()) {
+            std::thread::sleep(remaining);
+        }
+    }
+    save(&mut cpu, true);
+}
+
+fn save(cpu: &mut CPU, force: bool) {
+    if let Some(cartridge) = cpu.bus.cartridge.as_mut() {
+        let result = if force { cartridge.save() } else { cartridge.save_if_dirty() };
+        if let Err(error) = result {
+            println!("Could not write save file: {}", error);
+        }
+    }
+}
@@ -0,0 +1,639 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::cartridge::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+pub enum MBC {
+    RomOnly,
+    MBC1(MBC1),
+    MBC2(MBC2),
+    MBC3(MBC3),
+    MBC5(MBC5),
+}
+
+impl MBC {
+    pub fn rtc_mut(&mut self) -> Option<&mut RTC> {
+        match self {
+            MBC::MBC3(mbc) => mbc.rtc.as_mut(),
+            _ => None,
+        }
+    }
+
+    pub fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        match self {
+            MBC::RomOnly => rom.get(address as usize).copied().unwrap_or(0xFF),
+            MBC::MBC1(mbc) => mbc.read_rom(rom, address),
+            MBC::MBC2(mbc) => mbc.read_rom(rom, address),
+            MBC::MBC3(mbc) => mbc.read_rom(rom, address),
+            MBC::MBC5(mbc) => mbc.read_rom(rom, address),
+        }
+    }
+
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        match self {
+            MBC::RomOnly => {}
+            MBC::MBC1(mbc) => mbc.write_rom(address, value),
+            MBC::MBC2(mbc) => mbc.write_rom(address, value),
+            MBC::MBC3(mbc) => mbc.write_rom(address, value),
+            MBC::MBC5(mbc) => mbc.write_rom(address, value),
+        }
+    }
+
+    pub fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        match self {
+            MBC::RomOnly => ram.get((address & 0x1FFF) as usize).copied().unwrap_or(0xFF),
+            MBC::MBC1(mbc) => mbc.read_ram(ram, address),
+            MBC::MBC2(mbc) => mbc.read_ram(ram, address),
+            MBC::MBC3(mbc) => mbc.read_ram(ram, address),
+            MBC::MBC5(mbc) => mbc.read_ram(ram, address),
+        }
+    }
+
+    pub fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        match self {
+            MBC::RomOnly => {
+                if let Some(byte) = ram.get_mut((address & 0x1FFF) as usize) {
+                    *byte = value;
+                }
+            }
+            MBC::MBC1(mbc) => mbc.write_ram(ram, address, value),
+            MBC::MBC2(mbc) => mbc.write_ram(ram, address, value),
+            MBC::MBC3(mbc) => mbc.write_ram(ram, address, value),
+            MBC::MBC5(mbc) => mbc.write_ram(ram, address, value),
+        }
+    }
+}
+
+// Bank numbers wrap around the banks that actually exist, the unused upper bits are not wired
+fn rom_offset(rom: &[u8], bank: usize, address: u16) -> usize {
+    let banks = (rom.len() / ROM_BANK_SIZE).max(1);
+    (bank % banks) * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1))
+}
+
+fn ram_offset(ram: &[u8], bank: usize, address: u16) -> usize {
+    let banks = (ram.len() / RAM_BANK_SIZE).max(1);
+    ((bank % banks) * RAM_BANK_SIZE + (address as usize & (RAM_BANK_SIZE - 1))) % ram.len()
+}
+
+// MBC1
+pub struct MBC1 {
+    ram_enabled: bool,
+    // BANK1, the low five bits of the ROM bank number. Writing 0 selects 1
+    rom_bank: u8,
+    // BANK2, two bits used as the RAM bank or the upper ROM bank bits
+    upper_bank: u8,
+    // Mode 1 applies BANK2 to 0x0000-0x3FFF and external RAM as well
+    advanced_banking: bool,
+    // MBC1M multicarts only wire four bits of BANK1, so BANK2 starts at bit 4
+    multicart: bool,
+}
+
+impl MBC1 {
+    pub fn new(rom: &[u8]) -> MBC1 {
+        MBC1 {
+            ram_enabled: false,
+            rom_bank: 1,
+            upper_bank: 0,
+            advanced_banking: false,
+            multicart: MBC1::is_multicart(rom),
+        }
+    }
+
+    // Multicarts are 1 MiB images with a second game header, logo included, in bank 0x10
+    fn is_multicart(rom: &[u8]) -> bool {
+        const LOGO_BEGIN: usize = 0x0104;
+        const LOGO_END: usize = 0x0133;
+        const SECOND_GAME: usize = 0x10 * ROM_BANK_SIZE;
+        rom.len() == 0x100000
+            && rom[LOGO_BEGIN..=LOGO_END] == rom[SECOND_GAME + LOGO_BEGIN..=SECOND_GAME + LOGO_END]
+    }
+
+    fn upper_bank_shift(&self) -> u8 {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = match address {
+            0x0000..=0x3FFF => {
+                if self.advanced_banking {
+                    self.upper_bank << self.upper_bank_shift()
+                } else {
+                    0
+                }
+            }
+            _ => {
+                let low_bits = if self.multicart { self.rom_bank & 0x0F } else { self.rom_bank };
+                (self.upper_bank << self.upper_bank_shift()) | low_bits
+            }
+        };
+        rom[rom_offset(rom, bank as usize, address)]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = value & 0x1F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.upper_bank = value & 0b11,
+            _ => self.advanced_banking = value & 1 != 0,
+        }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.advanced_banking { self.upper_bank as usize } else { 0 }
+    }
+
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled || ram.is_empty() {
+            return 0xFF;
+        }
+        ram[ram_offset(ram, self.ram_bank(), address)]
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        if !self.ram_enabled || ram.is_empty() {
+            return;
+        }
+        let offset = ram_offset(ram, self.ram_bank(), address);
+        ram[offset] = value;
+    }
+}
+// MBC1
+
+// MBC2
+// 512 half-byte cells built into the controller, repeated across 0xA000-0xBFFF
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+pub struct MBC2 {
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl MBC2 {
+    pub fn new() -> MBC2 {
+        MBC2 {
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+        rom[rom_offset(rom, bank, address)]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        // Only the lower half of the ROM area has registers, bit 8 of the address picks which one
+        if address > 0x3FFF {
+            return;
+        }
+        if address & 0x0100 == 0 {
+            self.ram_enabled = value & 0x0F == 0x0A;
+        } else {
+            self.rom_bank = value & 0x0F;
+            if self.rom_bank == 0 {
+                self.rom_bank = 1;
+            }
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled || ram.is_empty() {
+            return 0xFF;
+        }
+        // Only the low nibble exists, the upper one floats high
+        ram[address as usize % MBC2_RAM_SIZE] | 0xF0
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        if !self.ram_enabled || ram.is_empty() {
+            return;
+        }
+        ram[address as usize % MBC2_RAM_SIZE] = value & 0x0F;
+    }
+}
+// MBC2
+
+// MBC3
+pub struct MBC3 {
+    ram_enabled: bool,
+    rom_bank: u8,
+    // 0x00-0x07 select a RAM bank, 0x08-0x0C map an RTC register instead
+    ram_bank: u8,
+    // A 0x00 write followed by 0x01 latches the clock
+    latch_armed: bool,
+    pub rtc: Option<RTC>,
+}
+
+impl MBC3 {
+    pub fn new(timer: bool) -> MBC3 {
+        MBC3 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            latch_armed: false,
+            rtc: if timer { Some(RTC::new()) } else { None },
+        }
+    }
+
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+        rom[rom_offset(rom, bank, address)]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = value & 0x7F;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            _ => {
+                if self.latch_armed && value == 0x01 {
+                    if let Some(rtc) = self.rtc.as_mut() {
+                        rtc.latch();
+                    }
+                }
+                self.latch_armed = value == 0x00;
+            }
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match self.ram_bank {
+            0x00..=0x07 if !ram.is_empty() => ram[ram_offset(ram, self.ram_bank as usize, address)],
+            0x08..=0x0C => self.rtc.as_ref().map_or(0xFF, |rtc| rtc.read(self.ram_bank)),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+        match self.ram_bank {
+            0x00..=0x07 if !ram.is_empty() => {
+                let offset = ram_offset(ram, self.ram_bank as usize, address);
+                ram[offset] = value;
+            }
+            0x08..=0x0C => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write(self.ram_bank, value);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+const RTC_SECONDS: u8 = 0x08;
+const RTC_MINUTES: u8 = 0x09;
+const RTC_HOURS: u8 = 0x0A;
+const RTC_DAY_LOW: u8 = 0x0B;
+const RTC_DAY_HIGH: u8 = 0x0C;
+
+const DAY_HIGH_BIT: u8 = 1 << 0;
+const HALT_BIT: u8 = 1 << 6;
+const DAY_CARRY_BIT: u8 = 1 << 7;
+
+// Current and latched registers as little endian u32s followed by a 64-bit UNIX timestamp,
+// the layout most other emulators append to MBC3 saves. Older files use a 32-bit timestamp
+pub const RTC_SAVE_SIZE: usize = 48;
+pub const RTC_SAVE_SIZE_LEGACY: usize = 44;
+
+pub struct RTC {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halted: bool,
+    day_carry: bool,
+    latched: [u8; 5],
+    // The clock runs off host time, so it keeps counting while the emulator is not running
+    last_update: SystemTime,
+    subsecond: Duration,
+}
+
+impl RTC {
+    pub fn new() -> RTC {
+        RTC {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halted: false,
+            day_carry: false,
+            latched: [0; 5],
+            last_update: SystemTime::now(),
+            subsecond: Duration::ZERO,
+        }
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            (self.days & 0xFF) as u8,
+            ((self.days >> 8) as u8 & DAY_HIGH_BIT)
+                | if self.halted { HALT_BIT } else { 0 }
+                | if self.day_carry { DAY_CARRY_BIT } else { 0 },
+        ]
+    }
+
+    // Catches the counters up with the host clock
+    fn update(&mut self) {
+        let now = SystemTime::now();
+        let elapsed = now.duration_since(self.last_update).unwrap_or(Duration::ZERO);
+        self.last_update = now;
+        if self.halted {
+            return;
+        }
+
+        let elapsed = elapsed + self.subsecond;
+        self.subsecond = Duration::from_nanos(elapsed.subsec_nanos() as u64);
+        self.advance(elapsed.as_secs());
+    }
+
+    fn advance(&mut self, seconds: u64) {
+        let total = self.seconds as u64
+            + self.minutes as u64 * 60
+            + self.hours as u64 * 60 * 60
+            + self.days as u64 * 60 * 60 * 24
+            + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / (60 * 60) % 24) as u8;
+        let days = total / (60 * 60 * 24);
+        if days > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (days & 0x1FF) as u16;
+    }
+
+    fn latch(&mut self) {
+        self.update();
+        self.latched = self.registers();
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        self.latched[(register - RTC_SECONDS) as usize]
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        self.update();
+        match register {
+            RTC_SECONDS => {
+                self.seconds = value & 0x3F;
+                self.subsecond = Duration::ZERO;
+            }
+            RTC_MINUTES => self.minutes = value & 0x3F,
+            RTC_HOURS => self.hours = value & 0x1F,
+            RTC_DAY_LOW => self.days = (self.days & 0x100) | value as u16,
+            RTC_DAY_HIGH => {
+                self.days = (self.days & 0xFF) | ((value & DAY_HIGH_BIT) as u16) << 8;
+                self.halted = value & HALT_BIT != 0;
+                self.day_carry = value & DAY_CARRY_BIT != 0;
+            }
+            _ => return,
+        }
+        // Reads only ever see latched values, but writes show up there straight away
+        self.latched[(register - RTC_SECONDS) as usize] = self.registers()[(register - RTC_SECONDS) as usize];
+    }
+
+    // Brings the clock up to date first so the timestamp matches the registers saved with it
+    pub fn save_bytes(&mut self) -> [u8; RTC_SAVE_SIZE] {
+        self.update();
+        let mut bytes = [0; RTC_SAVE_SIZE];
+        let registers = self.registers();
+        for i in 0..5 {
+            bytes[i * 4] = registers[i];
+            bytes[20 + i * 4] = self.latched[i];
+        }
+        let timestamp = self.last_update.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        bytes[40..48].copy_from_slice(&timestamp.to_le_bytes());
+        bytes
+    }
+
+    // Restores a saved clock and adds the time that passed since it was written
+    pub fn from_save_bytes(bytes: &[u8]) -> Option<RTC> {
+        if bytes.len() != RTC_SAVE_SIZE && bytes.len() != RTC_SAVE_SIZE_LEGACY {
+            return None;
+        }
+        let mut rtc = RTC::new();
+        rtc.seconds = bytes[0] & 0x3F;
+        rtc.minutes = bytes[4] & 0x3F;
+        rtc.hours = bytes[8] & 0x1F;
+        rtc.days = bytes[12] as u16 | ((bytes[16] & DAY_HIGH_BIT) as u16) << 8;
+        rtc.halted = bytes[16] & HALT_BIT != 0;
+        rtc.day_carry = bytes[16] & DAY_CARRY_BIT != 0;
+        for i in 0..5 {
+            rtc.latched[i] = bytes[20 + i * 4];
+        }
+        let mut timestamp = [0; 8];
+        timestamp[..bytes.len() - 40].copy_from_slice(&bytes[40..]);
+        rtc.last_update = UNIX_EPOCH + Duration::from_secs(u64::from_le_bytes(timestamp));
+        rtc.update();
+        Some(rtc)
+    }
+}
+// MBC3
+
+// MBC5
+pub struct MBC5 {
+    ram_enabled: bool,
+    // Nine bits, and unlike the older controllers bank 0 can be mapped to 0x4000-0x7FFF
+    rom_bank: u16,
+    ram_bank: u8,
+    // On rumble cartridges bit 3 of the RAM bank register drives the motor instead
+    has_rumble: bool,
+    rumble: bool,
+    rumble_callback: Option<Box<dyn FnMut(bool)>>,
+}
+
+impl MBC5 {
+    pub fn new(has_rumble: bool) -> MBC5 {
+        MBC5 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble: false,
+            rumble_callback: None,
+        }
+    }
+
+    // Called with the new motor state whenever the game switches it on or off
+    pub fn set_rumble_callback(&mut self, callback: Box<dyn FnMut(bool)>) {
+        self.rumble_callback = Some(callback);
+    }
+
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+        rom[rom_offset(rom, bank, address)]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((value & 1) as u16) << 8,
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    self.ram_bank = value & 0x07;
+                    self.set_rumble(value & 0x08 != 0);
+                } else {
+                    self.ram_bank = value & 0x0F;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn set_rumble(&mut self, rumble: bool) {
+        if self.rumble == rumble {
+            return;
+        }
+        self.rumble = rumble;
+        if let Some(callback) = self.rumble_callback.as_mut() {
+            callback(rumble);
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], address: u16) -> u8 {
+        if !self.ram_enabled || ram.is_empty() {
+            return 0xFF;
+        }
+        ram[ram_offset(ram, self.ram_bank as usize, address)]
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], address: u16, value: u8) {
+        if !self.ram_enabled || ram.is_empty() {
+            return;
+        }
+        let offset = ram_offset(ram, self.ram_bank as usize, address);
+        ram[offset] = value;
+    }
+}
+// MBC5
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An MBC3 with a stopped clock and RAM enabled, so nothing moves unless a test says so
+    fn stopped_clock() -> MBC3 {
+        let mut mbc = MBC3::new(true);
+        mbc.write_rom(0x0000, 0x0A);
+        write_register(&mut mbc, RTC_DAY_HIGH, HALT_BIT);
+        mbc
+    }
+
+    fn write_register(mbc: &mut MBC3, register: u8, value: u8) {
+        mbc.write_rom(0x4000, register);
+        mbc.write_ram(&mut [], 0xA000, value);
+    }
+
+    fn read_register(mbc: &mut MBC3, register: u8) -> u8 {
+        mbc.write_rom(0x4000, register);
+        mbc.read_ram(&[], 0xA000)
+    }
+
+    fn latch(mbc: &mut MBC3) {
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+    }
+
+    #[test]
+    fn reads_come_from_the_latch() {
+        let mut mbc = stopped_clock();
+        write_register(&mut mbc, RTC_MINUTES, 5);
+        assert_eq!(read_register(&mut mbc, RTC_MINUTES), 5);
+
+        mbc.rtc.as_mut().unwrap().advance(60);
+        assert_eq!(read_register(&mut mbc, RTC_MINUTES), 5);
+        // 0x01 only latches right after a 0x00
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(read_register(&mut mbc, RTC_MINUTES), 5);
+        latch(&mut mbc);
+        assert_eq!(read_register(&mut mbc, RTC_MINUTES), 6);
+    }
+
+    #[test]
+    fn day_counter_overflow_sets_carry() {
+        let mut mbc = stopped_clock();
+        write_register(&mut mbc, RTC_SECONDS, 59);
+        write_register(&mut mbc, RTC_MINUTES, 59);
+        write_register(&mut mbc, RTC_HOURS, 23);
+        write_register(&mut mbc, RTC_DAY_LOW, 0xFF);
+        write_register(&mut mbc, RTC_DAY_HIGH, HALT_BIT | DAY_HIGH_BIT);
+
+        mbc.rtc.as_mut().unwrap().advance(1);
+        latch(&mut mbc);
+        assert_eq!(read_register(&mut mbc, RTC_DAY_LOW), 0x00);
+        assert_eq!(read_register(&mut mbc, RTC_DAY_HIGH), HALT_BIT | DAY_CARRY_BIT);
+
+        // The carry sticks until the game clears it
+        mbc.rtc.as_mut().unwrap().advance(60 * 60 * 24);
+        latch(&mut mbc);
+        assert_eq!(read_register(&mut mbc, RTC_DAY_LOW), 0x01);
+        assert_eq!(read_register(&mut mbc, RTC_DAY_HIGH), HALT_BIT | DAY_CARRY_BIT);
+        write_register(&mut mbc, RTC_DAY_HIGH, HALT_BIT);
+        assert_eq!(read_register(&mut mbc, RTC_DAY_HIGH), HALT_BIT);
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let mut mbc = stopped_clock();
+        write_register(&mut mbc, RTC_SECONDS, 12);
+        write_register(&mut mbc, RTC_MINUTES, 34);
+        write_register(&mut mbc, RTC_HOURS, 5);
+        write_register(&mut mbc, RTC_DAY_LOW, 0xAB);
+        write_register(&mut mbc, RTC_DAY_HIGH, HALT_BIT | DAY_CARRY_BIT | DAY_HIGH_BIT);
+        let rtc = mbc.rtc.as_mut().unwrap();
+        rtc.latched = [1, 2, 3, 4, 5];
+
+        let bytes = rtc.save_bytes();
+        assert_eq!(bytes[..20], [12, 0, 0, 0, 34, 0, 0, 0, 5, 0, 0, 0, 0xAB, 0, 0, 0, 0xC1, 0, 0, 0]);
+        assert_eq!(bytes[20..40], [1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0, 5, 0, 0, 0]);
+
+        let loaded = RTC::from_save_bytes(&bytes).unwrap();
+        assert_eq!(loaded.registers(), rtc.registers());
+        assert_eq!(loaded.latched, rtc.latched);
+        assert_eq!(loaded.last_update.duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            rtc.last_update.duration_since(UNIX_EPOCH).unwrap().as_secs());
+    }
+
+    #[test]
+    fn loads_legacy_save_and_catches_up() {
+        // A running clock saved 90 seconds ago with a 32-bit timestamp
+        let saved_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() - 90;
+        let mut bytes = [0; RTC_SAVE_SIZE_LEGACY];
+        bytes[0] = 10;
+        bytes[20] = 7;
+        bytes[40..].copy_from_slice(&(saved_at as u32).to_le_bytes());
+
+        let rtc = RTC::from_save_bytes(&bytes).unwrap();
+        assert_eq!(rtc.registers(), [40, 1, 0, 0, 0]);
+        assert_eq!(rtc.latched, [7, 0, 0, 0, 0]);
+
+        assert!(RTC::from_save_bytes(&bytes[..RTC_SAVE_SIZE_LEGACY - 1]).is_none());
+    }
+}
@@ -0,0 +1,711 @@
+use std::collections::VecDeque;
+use crate::emulator::Interrupt;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const VRAM_BEGIN: u16 = 0x8000;
+const VRAM_END: u16 = 0x9FFF;
+const VRAM_SIZE: usize = (VRAM_END - VRAM_BEGIN + 1) as usize;
+
+const OAM_BEGIN: u16 = 0xFE00;
+const OAM_END: u16 = 0xFE9F;
+const OAM_SIZE: usize = (OAM_END - OAM_BEGIN + 1) as usize;
+
+const LCDC: u16 = 0xFF40;
+const STAT: u16 = 0xFF41;
+const SCY: u16 = 0xFF42;
+const SCX: u16 = 0xFF43;
+const LY: u16 = 0xFF44;
+const LYC: u16 = 0xFF45;
+const BGP: u16 = 0xFF47;
+const OBP0: u16 = 0xFF48;
+const OBP1: u16 = 0xFF49;
+const WY: u16 = 0xFF4A;
+const WX: u16 = 0xFF4B;
+
+const LCDC_BG_ENABLE: u8 = 1 << 0;
+const LCDC_OBJ_ENABLE: u8 = 1 << 1;
+const LCDC_OBJ_SIZE: u8 = 1 << 2;
+const LCDC_BG_MAP: u8 = 1 << 3;
+const LCDC_TILE_DATA: u8 = 1 << 4;
+const LCDC_WINDOW_ENABLE: u8 = 1 << 5;
+const LCDC_WINDOW_MAP: u8 = 1 << 6;
+const LCDC_LCD_ENABLE: u8 = 1 << 7;
+
+// Offsets into VRAM of the two 32x32 tile maps
+const TILE_MAP_0: usize = 0x1800;
+const TILE_MAP_1: usize = 0x1C00;
+
+const STAT_LYC_EQUAL: u8 = 1 << 2;
+const STAT_HBLANK_INTERRUPT: u8 = 1 << 3;
+const STAT_VBLANK_INTERRUPT: u8 = 1 << 4;
+const STAT_OAM_INTERRUPT: u8 = 1 << 5;
+const STAT_LYC_INTERRUPT: u8 = 1 << 6;
+
+const OBJ_BEHIND_BG: u8 = 1 << 7;
+const OBJ_Y_FLIP: u8 = 1 << 6;
+const OBJ_X_FLIP: u8 = 1 << 5;
+const OBJ_PALETTE: u8 = 1 << 4;
+
+const SPRITES_PER_LINE: usize = 10;
+
+const DOTS_PER_LINE: u16 = 456;
+const LINES_PER_FRAME: u8 = 154;
+const OAM_SCAN_DOTS: u16 = 80;
+// Drawing takes at least 172 dots, longer with fine scrolling, the window or sprites on the line
+const MIN_DRAWING_DOTS: u16 = 172;
+const MAX_DRAWING_DOTS: u16 = 289;
+
+// The mode the PPU is in, as reported in the low two bits of STAT
+#[derive(Copy,Clone,PartialEq)]
+pub enum Mode {
+    HBlank,
+    VBlank,
+    OamScan,
+    Drawing,
+}
+
+impl Mode {
+    fn bits(&self) -> u8 {
+        match self {
+            Mode::HBlank => 0,
+            Mode::VBlank => 1,
+            Mode::OamScan => 2,
+            Mode::Drawing => 3,
+        }
+    }
+}
+
+// How pixels are produced during mode 3
+#[derive(Copy,Clone,PartialEq,Debug)]
+pub enum Renderer {
+    // Draws each line in one go at the end of mode 3. Fast, but blind to mid-line register writes.
+    Scanline,
+    // Runs the background fetcher and pixel FIFOs dot by dot like the hardware does
+    Fifo,
+}
+
+#[derive(Copy,Clone,PartialEq)]
+enum FetcherStep {
+    GetTile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+// A pixel waiting in the sprite FIFO. Color 0 is transparent.
+#[derive(Copy,Clone)]
+struct SpritePixel {
+    color: u8,
+    obp1: bool,
+    behind_bg: bool,
+}
+
+// State of the FIFO renderer for the line being drawn
+struct Fifo {
+    background: VecDeque<u8>,
+    sprites: VecDeque<SpritePixel>,
+    step: FetcherStep,
+    // Each fetcher step but the push takes two dots
+    step_dots: u8,
+    // Tile column the fetcher is on, relative to SCX for the background or to the window's left edge
+    fetcher_x: u8,
+    tile_index: u8,
+    tile_low: u8,
+    tile_high: u8,
+    // The fetcher has switched to the window for the rest of the line
+    window: bool,
+    // Next pixel to be shifted out to the LCD
+    x: u8,
+    // Pixels still to be thrown away, for the first fetch of the line and fine scrolling
+    discard: u8,
+    // Dots left fetching a sprite, the FIFOs are frozen meanwhile
+    stall: u8,
+    // Sprites of the line sorted by X, and the next one to be fetched
+    line_sprites: Vec<Sprite>,
+    next_sprite: usize,
+}
+
+impl Fifo {
+    fn new() -> Fifo {
+        Fifo {
+            background: VecDeque::with_capacity(16),
+            sprites: VecDeque::with_capacity(8),
+            step: FetcherStep::GetTile,
+            step_dots: 0,
+            fetcher_x: 0,
+            tile_index: 0,
+            tile_low: 0,
+            tile_high: 0,
+            window: false,
+            x: 0,
+            discard: 0,
+            stall: 0,
+            line_sprites: Vec::with_capacity(SPRITES_PER_LINE),
+            next_sprite: 0,
+        }
+    }
+}
+
+// An OAM entry. Y and X are stored with offsets of 16 and 8 so sprites can sit partly off screen
+#[derive(Copy,Clone)]
+struct Sprite {
+    y: u8,
+    x: u8,
+    tile: u8,
+    attributes: u8,
+}
+
+pub struct Ppu {
+    pub vram: [u8; VRAM_SIZE],
+    pub oam: [u8; OAM_SIZE],
+    pub lcdc: u8,
+    pub stat: u8,
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+    pub mode: Mode,
+    // One shade from 0 (white) to 3 (black) per pixel, row by row
+    pub framebuffer: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    // Set when a finished frame is in the framebuffer, the frontend clears it once shown
+    pub frame_ready: bool,
+    // Position within the current line, in T-cycles
+    dot: u16,
+    // Length of mode 3 on the current line, in dots
+    drawing_dots: u16,
+    // All STAT interrupt sources are ORed into one line, the interrupt fires when it goes from low to high
+    stat_line: bool,
+    // The window keeps its own line counter, it only advances on lines where the window was drawn
+    window_line: u8,
+    // The window only shows up once LY has matched WY during the frame
+    window_triggered: bool,
+    // Sprites found by the OAM scan for the current line, in OAM order
+    line_sprites: Vec<Sprite>,
+    renderer: Renderer,
+    fifo: Fifo,
+}
+
+impl Ppu {
+    pub fn new(renderer: Renderer) -> Ppu {
+        Ppu {
+            vram: [0; VRAM_SIZE],
+            oam: [0; OAM_SIZE],
+            lcdc: 0,
+            stat: 0,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            framebuffer: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            mode: Mode::HBlank,
+            frame_ready: false,
+            dot: 0,
+            drawing_dots: MIN_DRAWING_DOTS,
+            stat_line: false,
+            window_line: 0,
+            window_triggered: false,
+            line_sprites: Vec::with_capacity(SPRITES_PER_LINE),
+            renderer,
+            fifo: Fifo::new(),
+        }
+    }
+
+    // Advances one machine cycle, which is four dots. Returns the interrupt flags to request.
+    pub fn tick(&mut self) -> u8 {
+        if self.lcdc & LCDC_LCD_ENABLE == 0 {
+            return 0;
+        }
+        let mut interrupts = 0;
+        for _ in 0..4 {
+            interrupts |= self.tick_dot();
+        }
+        interrupts
+    }
+
+    fn tick_dot(&mut self) -> u8 {
+        let mut interrupts = 0;
+        if self.dot == 0 && self.ly == self.wy {
+            self.window_triggered = true;
+        }
+        self.dot += 1;
+        match self.mode {
+            Mode::OamScan => {
+                if self.dot == OAM_SCAN_DOTS {
+                    self.scan_oam();
+                    match self.renderer {
+                        Renderer::Scanline => self.drawing_dots = self.drawing_dots(),
+                        Renderer::Fifo => self.start_fifo(),
+                    }
+                    self.mode = Mode::Drawing;
+                }
+            }
+            Mode::Drawing => match self.renderer {
+                Renderer::Scanline => {
+                    if self.dot == OAM_SCAN_DOTS + self.drawing_dots {
+                        self.render_scanline();
+                        self.mode = Mode::HBlank;
+                    }
+                }
+                Renderer::Fifo => {
+                    if self.step_fifo() {
+                        if self.fifo.window {
+                            self.window_line += 1;
+                        }
+                        self.mode = Mode::HBlank;
+                    }
+                }
+            },
+            Mode::HBlank => {
+                if self.dot >= DOTS_PER_LINE {
+                    self.dot = 0;
+                    self.ly += 1;
+                    if self.ly as usize == SCREEN_HEIGHT {
+                        self.mode = Mode::VBlank;
+                        self.frame_ready = true;
+                        interrupts |= Interrupt::VBlank.mask();
+                    } else {
+                        self.mode = Mode::OamScan;
+                    }
+                }
+            }
+            Mode::VBlank => {
+                if self.dot >= DOTS_PER_LINE {
+                    self.dot = 0;
+                    self.ly += 1;
+                    if self.ly == LINES_PER_FRAME {
+                        self.ly = 0;
+                        self.window_line = 0;
+                        self.window_triggered = false;
+                        self.mode = Mode::OamScan;
+                    }
+                }
+            }
+        }
+
+        let stat_line = self.stat_line();
+        if stat_line && !self.stat_line {
+            interrupts |= Interrupt::LCDStat.mask();
+        }
+        self.stat_line = stat_line;
+        interrupts
+    }
+
+    // While any enabled source keeps the line high, other sources cannot raise another interrupt
+    fn stat_line(&self) -> bool {
+        let mode_source = match self.mode {
+            Mode::HBlank => STAT_HBLANK_INTERRUPT,
+            Mode::VBlank => STAT_VBLANK_INTERRUPT,
+            Mode::OamScan => STAT_OAM_INTERRUPT,
+            Mode::Drawing => 0,
+        };
+        (self.stat & mode_source != 0) || (self.stat & STAT_LYC_INTERRUPT != 0 && self.ly == self.lyc)
+    }
+
+    // The fetcher stalls for the pixels SCX discards, for the window restarting it and for every sprite
+    fn drawing_dots(&self) -> u16 {
+        let mut dots = MIN_DRAWING_DOTS + (self.scx % 8) as u16;
+        if self.window_visible() {
+            dots += 6;
+        }
+        if self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            for sprite in self.line_sprites.iter() {
+                dots += 11 - ((sprite.x as u16 + self.scx as u16) % 8).min(5);
+            }
+        }
+        dots.min(MAX_DRAWING_DOTS)
+    }
+
+    // WX is the window's left edge plus 7, so values below 7 start it off screen
+    fn window_visible(&self) -> bool {
+        self.lcdc & LCDC_BG_ENABLE != 0
+            && self.lcdc & LCDC_WINDOW_ENABLE != 0
+            && self.window_triggered
+            && self.wx < 167
+    }
+
+    fn render_scanline(&mut self) {
+        // Background color indices, sprites need them for their priority
+        let mut line = [0u8; SCREEN_WIDTH];
+
+        // On DMG clearing LCDC bit 0 blanks both the background and the window
+        if self.lcdc & LCDC_BG_ENABLE != 0 {
+            let map = if self.lcdc & LCDC_BG_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+            let y = self.scy.wrapping_add(self.ly);
+            for (x, pixel) in line.iter_mut().enumerate() {
+                *pixel = self.map_pixel(map, self.scx.wrapping_add(x as u8), y);
+            }
+
+            if self.window_visible() {
+                let map = if self.lcdc & LCDC_WINDOW_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+                let left = self.wx as i16 - 7;
+                for x in left.max(0)..SCREEN_WIDTH as i16 {
+                    line[x as usize] = self.map_pixel(map, (x - left) as u8, self.window_line);
+                }
+                self.window_line += 1;
+            }
+        }
+
+        let row = self.ly as usize * SCREEN_WIDTH;
+        for (x, &color) in line.iter().enumerate() {
+            self.framebuffer[row + x] = if self.lcdc & LCDC_BG_ENABLE != 0 {
+                palette_shade(self.bgp, color)
+            } else {
+                0
+            };
+        }
+
+        if self.lcdc & LCDC_OBJ_ENABLE != 0 {
+            self.render_sprites(&line);
+        }
+    }
+
+    fn start_fifo(&mut self) {
+        let fifo = &mut self.fifo;
+        fifo.background.clear();
+        fifo.sprites.clear();
+        fifo.step = FetcherStep::GetTile;
+        fifo.step_dots = 0;
+        // The first tile fetched on every line is thrown away, so the fetcher starts one column early
+        fifo.fetcher_x = 0xFF;
+        fifo.window = false;
+        fifo.x = 0;
+        fifo.discard = 8 + self.scx % 8;
+        fifo.stall = 0;
+        fifo.line_sprites.clear();
+        fifo.line_sprites.extend_from_slice(&self.line_sprites);
+        fifo.line_sprites.sort_by_key(|sprite| sprite.x);
+        fifo.next_sprite = 0;
+    }
+
+    // Runs the FIFO renderer for one dot, returns true once the whole line has been shifted out
+    fn step_fifo(&mut self) -> bool {
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
+
+        // Reaching WX restarts the fetcher on the window, dropping whatever background was queued
+        if !self.fifo.window && self.window_visible() && self.fifo.x as i16 >= self.wx as i16 - 7 {
+            self.fifo.window = true;
+            self.fifo.background.clear();
+            self.fifo.step = FetcherStep::GetTile;
+            self.fifo.step_dots = 0;
+            self.fifo.fetcher_x = 0;
+            self.fifo.discard = 7u8.saturating_sub(self.wx);
+        }
+
+        // A sprite starting at this pixel freezes the FIFOs while its tile is fetched
+        if self.fifo.discard == 0 && self.fetch_sprites() {
+            return false;
+        }
+
+        if let Some(color) = self.fifo.background.pop_front() {
+            if self.fifo.discard > 0 {
+                self.fifo.discard -= 1;
+            } else {
+                let sprite = self.fifo.sprites.pop_front();
+                let shade = self.mix_pixel(color, sprite);
+                self.framebuffer[self.ly as usize * SCREEN_WIDTH + self.fifo.x as usize] = shade;
+                self.fifo.x += 1;
+            }
+        }
+        self.step_fetcher();
+
+        self.fifo.x as usize == SCREEN_WIDTH
+    }
+
+    // Merges every sprite that starts at the current pixel into the sprite FIFO. Pixels already
+    // queued win over new ones, which together with the X sort gives the DMG priority order.
+    fn fetch_sprites(&mut self) -> bool {
+        if self.lcdc & LCDC_OBJ_ENABLE == 0 {
+            return false;
+        }
+        let height = self.sprite_height();
+        let mut fetched = false;
+        while let Some(&sprite) = self.fifo.line_sprites.get(self.fifo.next_sprite) {
+            let left = sprite.x as i16 - 8;
+            if left.max(0) > self.fifo.x as i16 {
+                break;
+            }
+            self.fifo.next_sprite += 1;
+            fetched = true;
+            self.fifo.stall += 6;
+
+            // Sprites hanging off the left edge only queue their visible columns
+            let first_column = (self.fifo.x as i16 - left) as u8;
+            for column in first_column..8 {
+                let pixel = SpritePixel {
+                    color: self.sprite_pixel(&sprite, height, column),
+                    obp1: sprite.attributes & OBJ_PALETTE != 0,
+                    behind_bg: sprite.attributes & OBJ_BEHIND_BG != 0,
+                };
+                let index = (column - first_column) as usize;
+                match self.fifo.sprites.get_mut(index) {
+                    Some(queued) => {
+                        if queued.color == 0 {
+                            *queued = pixel;
+                        }
+                    }
+                    None => self.fifo.sprites.push_back(pixel),
+                }
+            }
+        }
+        if fetched {
+            // The dot spent finding the sprite counts too
+            self.fifo.stall -= 1;
+        }
+        fetched
+    }
+
+    // Palettes and enable bits are read as each pixel leaves the FIFO, which is what makes mid-line writes visible
+    fn mix_pixel(&self, color: u8, sprite: Option<SpritePixel>) -> u8 {
+        let background = if self.lcdc & LCDC_BG_ENABLE != 0 { color } else { 0 };
+        if let Some(sprite) = sprite {
+            let hidden = sprite.behind_bg && background != 0;
+            if sprite.color != 0 && !hidden && self.lcdc & LCDC_OBJ_ENABLE != 0 {
+                let palette = if sprite.obp1 { self.obp1 } else { self.obp0 };
+                return palette_shade(palette, sprite.color);
+            }
+        }
+        if self.lcdc & LCDC_BG_ENABLE != 0 {
+            palette_shade(self.bgp, background)
+        } else {
+            0
+        }
+    }
+
+    fn step_fetcher(&mut self) {
+        if self.fifo.step == FetcherStep::Push {
+            // The fetcher can only push a whole tile row once the background FIFO has run dry
+            if self.fifo.background.is_empty() {
+                let (low, high) = (self.fifo.tile_low, self.fifo.tile_high);
+                for bit in (0..8).rev() {
+                    self.fifo.background.push_back((((high >> bit) & 1) << 1) | ((low >> bit) & 1));
+                }
+                self.fifo.fetcher_x = self.fifo.fetcher_x.wrapping_add(1);
+                self.fifo.step = FetcherStep::GetTile;
+            }
+            return;
+        }
+
+        self.fifo.step_dots += 1;
+        if self.fifo.step_dots < 2 {
+            return;
+        }
+        self.fifo.step_dots = 0;
+        match self.fifo.step {
+            FetcherStep::GetTile => {
+                self.fifo.tile_index = self.vram[self.fetcher_map_address()];
+                self.fifo.step = FetcherStep::DataLow;
+            }
+            FetcherStep::DataLow => {
+                self.fifo.tile_low = self.vram[self.fetcher_tile_address()];
+                self.fifo.step = FetcherStep::DataHigh;
+            }
+            FetcherStep::DataHigh => {
+                self.fifo.tile_high = self.vram[self.fetcher_tile_address() + 1];
+                self.fifo.step = FetcherStep::Push;
+            }
+            FetcherStep::Push => {}
+        }
+    }
+
+    // The scroll registers are read on every fetch, not once per line
+    fn fetcher_map_address(&self) -> usize {
+        if self.fifo.window {
+            let map = if self.lcdc & LCDC_WINDOW_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+            map + (self.window_line as usize / 8) * 32 + (self.fifo.fetcher_x & 31) as usize
+        } else {
+            let map = if self.lcdc & LCDC_BG_MAP != 0 { TILE_MAP_1 } else { TILE_MAP_0 };
+            let y = self.scy.wrapping_add(self.ly);
+            let x = (self.scx / 8).wrapping_add(self.fifo.fetcher_x) & 31;
+            map + (y as usize / 8) * 32 + x as usize
+        }
+    }
+
+    fn fetcher_tile_address(&self) -> usize {
+        let row = if self.fifo.window {
+            self.window_line % 8
+        } else {
+            self.scy.wrapping_add(self.ly) % 8
+        };
+        self.tile_address(self.fifo.tile_index) + row as usize * 2
+    }
+
+    // Picks the first ten sprites in OAM that overlap the current line. Their X position does not
+    // matter, sprites off the left or right edge still use up a slot.
+    fn scan_oam(&mut self) {
+        let height = self.sprite_height();
+        self.line_sprites.clear();
+        for entry in self.oam.chunks(4) {
+            let sprite = Sprite { y: entry[0], x: entry[1], tile: entry[2], attributes: entry[3] };
+            let top = sprite.y as i16 - 16;
+            let line = self.ly as i16;
+            if line >= top && line < top + height as i16 {
+                self.line_sprites.push(sprite);
+                if self.line_sprites.len() == SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn render_sprites(&mut self, background: &[u8; SCREEN_WIDTH]) {
+        // On DMG the sprite with the smaller X wins, ties go to the one earlier in OAM.
+        // The sort is stable so OAM order is kept among equal X.
+        let mut sprites = self.line_sprites.clone();
+        sprites.sort_by_key(|sprite| sprite.x);
+
+        let height = self.sprite_height();
+        let row = self.ly as usize * SCREEN_WIDTH;
+        for (x, &background_color) in background.iter().enumerate() {
+            // The highest priority sprite with a visible pixel here decides, even if it is behind the background
+            let pixel = sprites.iter().find_map(|sprite| {
+                let column = x as i16 - (sprite.x as i16 - 8);
+                if !(0..8).contains(&column) {
+                    return None;
+                }
+                let color = self.sprite_pixel(sprite, height, column as u8);
+                if color == 0 { None } else { Some((sprite, color)) }
+            });
+            if let Some((sprite, color)) = pixel {
+                if sprite.attributes & OBJ_BEHIND_BG != 0 && background_color != 0 {
+                    continue;
+                }
+                let palette = if sprite.attributes & OBJ_PALETTE != 0 { self.obp1 } else { self.obp0 };
+                self.framebuffer[row + x] = palette_shade(palette, color);
+            }
+        }
+    }
+
+    fn sprite_height(&self) -> u8 {
+        if self.lcdc & LCDC_OBJ_SIZE != 0 { 16 } else { 8 }
+    }
+
+    // Color index of one pixel of a sprite on the current line, 0 being transparent
+    fn sprite_pixel(&self, sprite: &Sprite, height: u8, column: u8) -> u8 {
+        // LCDC can switch from 8x16 to 8x8 after the OAM scan, so only the row bits of the current size count
+        let mut y = (self.ly as i16 - (sprite.y as i16 - 16)) as u8 & (height - 1);
+        if sprite.attributes & OBJ_Y_FLIP != 0 {
+            y = height - 1 - y;
+        }
+        let x = if sprite.attributes & OBJ_X_FLIP != 0 { 7 - column } else { column };
+        // Tall sprites ignore the low bit of the tile index, the bottom half is the next tile
+        let tile = if height == 16 { sprite.tile & 0xFE } else { sprite.tile };
+        // Sprites always use unsigned tile indices from 0x8000
+        self.tile_pixel(tile as usize * 16, x, y)
+    }
+
+    // Color index (0-3) of a pixel in a 256x256 tile map
+    fn map_pixel(&self, map: usize, x: u8, y: u8) -> u8 {
+        let tile_index = self.vram[map + (y as usize / 8) * 32 + x as usize / 8];
+        self.tile_pixel(self.tile_address(tile_index), x % 8, y % 8)
+    }
+
+    // Background and window tiles use either unsigned indices from 0x8000 or signed ones around 0x9000
+    fn tile_address(&self, tile_index: u8) -> usize {
+        if self.lcdc & LCDC_TILE_DATA != 0 {
+            tile_index as usize * 16
+        } else {
+            (0x1000 + tile_index as i8 as i32 * 16) as usize
+        }
+    }
+
+    // Each tile row is two bytes, the first holding the low bit of every pixel and the second the high bit
+    fn tile_pixel(&self, tile_address: usize, x: u8, y: u8) -> u8 {
+        let low = self.vram[tile_address + y as usize * 2];
+        let high = self.vram[tile_address + y as usize * 2 + 1];
+        let bit = 7 - x;
+        (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+    }
+
+    // The CPU cannot reach VRAM while the PPU draws from it, nor OAM while it scans or draws sprites.
+    // Reads return 0xFF and writes are dropped. With the LCD off both are always accessible.
+    fn vram_locked(&self) -> bool {
+        self.mode == Mode::Drawing
+    }
+
+    fn oam_locked(&self) -> bool {
+        self.mode == Mode::OamScan || self.mode == Mode::Drawing
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            VRAM_BEGIN..=VRAM_END if self.vram_locked() => 0xFF,
+            VRAM_BEGIN..=VRAM_END => self.vram[(address - VRAM_BEGIN) as usize],
+            OAM_BEGIN..=OAM_END if self.oam_locked() => 0xFF,
+            OAM_BEGIN..=OAM_END => self.oam[(address - OAM_BEGIN) as usize],
+            LCDC => self.lcdc,
+            STAT => {
+                let lyc_equal = if self.ly == self.lyc { STAT_LYC_EQUAL } else { 0 };
+                0x80 | self.stat | lyc_equal | self.mode.bits()
+            }
+            SCY => self.scy,
+            SCX => self.scx,
+            LY => self.ly,
+            LYC => self.lyc,
+            BGP => self.bgp,
+            OBP0 => self.obp0,
+            OBP1 => self.obp1,
+            WY => self.wy,
+            WX => self.wx,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            VRAM_BEGIN..=VRAM_END if self.vram_locked() => {}
+            VRAM_BEGIN..=VRAM_END => self.vram[(address - VRAM_BEGIN) as usize] = value,
+            OAM_BEGIN..=OAM_END if self.oam_locked() => {}
+            OAM_BEGIN..=OAM_END => self.oam[(address - OAM_BEGIN) as usize] = value,
+            LCDC => {
+                // Switching the LCD off resets it to the top of the screen in mode 0, and it shows white
+                // until the next frame. It starts over with an OAM scan when switched back on.
+                if value & LCDC_LCD_ENABLE == 0 && self.lcdc & LCDC_LCD_ENABLE != 0 {
+                    self.ly = 0;
+                    self.dot = 0;
+                    self.mode = Mode::HBlank;
+                    self.stat_line = false;
+                    self.window_line = 0;
+                    self.window_triggered = false;
+                    self.framebuffer = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+                    self.frame_ready = true;
+                }
+                if value & LCDC_LCD_ENABLE != 0 && self.lcdc & LCDC_LCD_ENABLE == 0 {
+                    self.mode = Mode::OamScan;
+                }
+                self.lcdc = value;
+            }
+            // Only the interrupt select bits of STAT are writable
+            STAT => self.stat = value & 0x78,
+            SCY => self.scy = value,
+            SCX => self.scx = value,
+            LY => {}
+            LYC => self.lyc = value,
+            BGP => self.bgp = value,
+            OBP0 => self.obp0 = value,
+            OBP1 => self.obp1 = value,
+            WY => self.wy = value,
+            WX => self.wx = value,
+            _ => {}
+        }
+    }
+}
+
+// Palette registers hold a two-bit shade for each of the four color indices
+fn palette_shade(palette: u8, color: u8) -> u8 {
+    (palette >> (color * 2)) & 0b11
+}
@@ -0,0 +1,69 @@
+const SB: u16 = 0xFF01;
+const SC: u16 = 0xFF02;
+
+const SC_TRANSFER: u8 = 1 << 7;
+const SC_INTERNAL_CLOCK: u8 = 1 << 0;
+
+// The internal clock runs at 8192 Hz, one bit every 128 machine cycles
+const CYCLES_PER_BIT: u16 = 128;
+
+pub struct Serial {
+    pub data: u8,
+    pub control: u8,
+    // Machine cycles until the next bit is shifted
+    counter: u16,
+    bits_left: u8,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            control: 0,
+            counter: 0,
+            bits_left: 0,
+        }
+    }
+
+    // Advances one machine cycle, returns true when the serial interrupt should be requested.
+    // Nothing is ever plugged into the link port, so every bit shifted in is a 1.
+    pub fn tick(&mut self) -> bool {
+        if self.control & (SC_TRANSFER | SC_INTERNAL_CLOCK) != SC_TRANSFER | SC_INTERNAL_CLOCK {
+            return false;
+        }
+        self.counter -= 1;
+        if self.counter > 0 {
+            return false;
+        }
+        self.data = (self.data << 1) | 1;
+        self.bits_left -= 1;
+        if self.bits_left > 0 {
+            self.counter = CYCLES_PER_BIT;
+            return false;
+        }
+        self.control &= !SC_TRANSFER;
+        true
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            SB => self.data,
+            SC => self.control | 0x7E,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            SB => self.data = value,
+            SC => {
+                self.control = value & (SC_TRANSFER | SC_INTERNAL_CLOCK);
+                if self.control & SC_TRANSFER != 0 {
+                    self.counter = CYCLES_PER_BIT;
+                    self.bits_left = 8;
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,185 @@
+const DIV: u16 = 0xFF04;
+const TIMA: u16 = 0xFF05;
+const TMA: u16 = 0xFF06;
+const TAC: u16 = 0xFF07;
+
+const TAC_ENABLE: u8 = 1 << 2;
+
+pub struct Timer {
+    // The internal 16-bit divider, counted in T-cycles. DIV is its upper byte
+    pub div: u16,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+    // TIMA overflowed last cycle and reads 0x00 until it is reloaded at the end of this one
+    overflow_pending: bool,
+    // TIMA was reloaded from TMA this cycle, TIMA writes are lost and TMA writes pass through
+    reloading: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            div: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            overflow_pending: false,
+            reloading: false,
+        }
+    }
+
+    // Advances one machine cycle, returns true when the timer interrupt should be requested
+    pub fn tick(&mut self) -> bool {
+        let mut interrupt = false;
+        self.reloading = false;
+        if self.overflow_pending {
+            self.overflow_pending = false;
+            self.tima = self.tma;
+            self.reloading = true;
+            interrupt = true;
+        }
+        self.set_div(self.div.wrapping_add(4));
+        interrupt
+    }
+
+    // TIMA counts falling edges of one divider bit ANDed with the enable bit, which is
+    // why resetting DIV or rewriting TAC can bump TIMA on its own
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0b11 {
+            0b00 => 9,
+            0b01 => 3,
+            0b10 => 5,
+            _ => 7,
+        };
+        self.tac & TAC_ENABLE != 0 && (self.div >> bit) & 1 != 0
+    }
+
+    fn set_div(&mut self, value: u16) {
+        let old_signal = self.signal();
+        self.div = value;
+        if old_signal && !self.signal() {
+            self.increment_tima();
+        }
+    }
+
+    fn increment_tima(&mut self) {
+        let (value, did_overflow) = self.tima.overflowing_add(1);
+        self.tima = value;
+        if did_overflow {
+            self.overflow_pending = true;
+        }
+    }
+
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            DIV => (self.div >> 8) as u8,
+            TIMA => self.tima,
+            TMA => self.tma,
+            TAC => self.tac | 0xF8,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            DIV => self.set_div(0),
+            // Writing during the delay cancels the pending reload and interrupt
+            TIMA if !self.reloading => {
+                self.overflow_pending = false;
+                self.tima = value;
+            }
+            TMA => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            TAC => {
+                let old_signal = self.signal();
+                self.tac = value & 0b111;
+                if old_signal && !self.signal() {
+                    self.increment_tima();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts every 4 machine cycles, TIMA one step from overflowing
+    fn about_to_overflow() -> Timer {
+        let mut timer = Timer::new();
+        timer.write_byte(TAC, 0x05);
+        timer.write_byte(TIMA, 0xFF);
+        timer.write_byte(TMA, 0xAB);
+        timer
+    }
+
+    // Runs up to the cycle where TIMA overflows
+    fn overflow(timer: &mut Timer) {
+        for _ in 0..4 {
+            assert!(!timer.tick());
+        }
+        assert_eq!(timer.read_byte(TIMA), 0x00);
+    }
+
+    #[test]
+    fn reloads_one_cycle_after_overflow() {
+        let mut timer = about_to_overflow();
+        overflow(&mut timer);
+        assert!(timer.tick());
+        assert_eq!(timer.read_byte(TIMA), 0xAB);
+        assert!(!timer.tick());
+    }
+
+    #[test]
+    fn tima_write_cancels_reload() {
+        let mut timer = about_to_overflow();
+        overflow(&mut timer);
+        timer.write_byte(TIMA, 0x42);
+        assert!(!timer.tick());
+        assert_eq!(timer.read_byte(TIMA), 0x42);
+    }
+
+    #[test]
+    fn writes_on_the_reload_cycle() {
+        let mut timer = about_to_overflow();
+        overflow(&mut timer);
+        assert!(timer.tick());
+        // TIMA writes are lost, TMA writes also land in TIMA
+        timer.write_byte(TIMA, 0x42);
+        assert_eq!(timer.read_byte(TIMA), 0xAB);
+        timer.write_byte(TMA, 0x33);
+        assert_eq!(timer.read_byte(TIMA), 0x33);
+        assert_eq!(timer.read_byte(TMA), 0x33);
+    }
+
+    #[test]
+    fn falling_edge_glitches() {
+        // Resetting DIV while the selected bit is set counts once
+        let mut timer = Timer::new();
+        timer.write_byte(TAC, 0x05);
+        timer.tick();
+        timer.tick();
+        timer.write_byte(DIV, 0x12);
+        assert_eq!(timer.read_byte(TIMA), 0x01);
+        assert_eq!(timer.read_byte(DIV), 0x00);
+
+        // So does disabling the timer
+        timer.tick();
+        timer.tick();
+        timer.write_byte(TAC, 0x01);
+        assert_eq!(timer.read_byte(TIMA), 0x02);
+
+        // Nothing counts while the timer is off or the selected bit is clear
+        timer.write_byte(DIV, 0x00);
+        timer.write_byte(TAC, 0x05);
+        timer.write_byte(DIV, 0x00);
+        assert_eq!(timer.read_byte(TIMA), 0x02);
+    }
+}